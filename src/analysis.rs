@@ -1,6 +1,6 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::cell::RefCell;
 use anyhow::Result;
@@ -8,112 +8,430 @@ use crate::node::Node;
 use crate::overlay2::analyze_overlay2;
 use crate::image::analyze_images;
 use crate::container::analyze_containers;
+use crate::containerd::analyze_containerd;
+use crate::journal::Journal;
+use crate::mountinfo::{mount_points, read_mountinfo};
+use crate::safety::ensure_daemon_stopped;
+use crate::trash::Remover;
 
-pub fn build_graph(base_path: &Path) -> Result<HashMap<String, Rc<RefCell<dyn Node>>>> {
+/// Where Docker keeps images and layers
+pub enum Store {
+    /// image/ and overlay2/ under the docker base directory
+    Overlay2,
+    /// The containerd image store, with its root directory
+    Containerd(PathBuf),
+}
+
+pub fn build_graph(base_path: &Path, store: &Store) -> Result<HashMap<String, Rc<RefCell<dyn Node>>>> {
     let mut graph = HashMap::new();
+    let mount_points = mount_points(&read_mountinfo());
 
-    analyze_overlay2(base_path, &mut graph)?;
-    analyze_images(base_path, &mut graph)?;
-    analyze_containers(base_path, &mut graph)?;
+    match store {
+        Store::Overlay2 => {
+            analyze_overlay2(base_path, &mut graph, &mount_points)?;
+            analyze_images(base_path, &mut graph)?;
+        }
+        Store::Containerd(containerd_path) => analyze_containerd(containerd_path, &mut graph)?,
+    }
+    analyze_containers(base_path, &mut graph, &mount_points)?;
 
     Ok(graph)
 }
 
+/// Every node of the graph once. Aliases, like `LayerDiffId:<diff>` for an image layer,
+/// are found by pointer, their key is not their id.
+pub fn unique_nodes(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> Vec<Rc<RefCell<dyn Node>>> {
+    let mut seen = HashSet::new();
+    graph.values()
+        .filter(|node| seen.insert(Rc::as_ptr(node) as *const ()))
+        .map(Rc::clone)
+        .collect()
+}
+
 pub fn classify_layers(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> HashMap<String, Vec<Rc<RefCell<dyn Node>>>> {
     let mut classified = HashMap::new();
-
-    for (key, node) in graph {
+    for node in unique_nodes(graph) {
         let id = node.borrow().id();
-        if &id != key { continue; }
         let node_type = id.split(':').next().unwrap_or("Unknown").to_string();
-        classified.entry(node_type).or_insert_with(Vec::new).push(Rc::clone(node));
+        classified.entry(node_type).or_insert_with(Vec::new).push(node);
     }
 
     classified
 }
 
-pub fn remove_node_list(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, node_id: &str, recursive: bool) -> Result<Vec<Rc<RefCell<dyn Node>>>> {
-    let mut result = Vec::new();
-    if recursive {
-        let mut visited = HashSet::new();
-        let mut stack = VecDeque::new();
-    
-        stack.push_back(Rc::clone(&graph.get(node_id).unwrap()));
-    
-        while let Some(current) = stack.pop_front() {
-            for dep in current.borrow().deps().iter().rev() {
-                if dep.borrow().rdeps().len() <= 1 {
-                    stack.push_back(dep.clone());
-                }
+/// Differences between two scans, by node id
+pub struct GraphDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Nodes in both scans whose deps or rdeps are not the same
+    pub changed: Vec<String>,
+}
+
+impl GraphDiff {
+    pub fn new(old: &HashMap<String, Rc<RefCell<dyn Node>>>, new: &HashMap<String, Rc<RefCell<dyn Node>>>) -> GraphDiff {
+        let old = relations(old);
+        let new = relations(new);
+
+        let mut added: Vec<String> = new.keys().filter(|id| !old.contains_key(*id)).cloned().collect();
+        let mut removed: Vec<String> = old.keys().filter(|id| !new.contains_key(*id)).cloned().collect();
+        let mut changed: Vec<String> = new.iter()
+            .filter(|(id, relations)| old.get(*id).is_some_and(|old_relations| old_relations != *relations))
+            .map(|(id, _)| id.clone())
+            .collect();
+        added.sort();
+        removed.sort();
+        changed.sort();
+        GraphDiff { added, removed, changed }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+// sorted ids of the deps and rdeps of each node, aliases are the same node
+fn relations(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> HashMap<String, (Vec<String>, Vec<String>)> {
+    graph.values()
+        .map(|node| {
+            let node = node.borrow();
+            let mut deps: Vec<String> = node.deps().iter().map(|dep| dep.borrow().id()).collect();
+            let mut rdeps: Vec<String> = node.rdeps().iter().map(|rdep| rdep.borrow().id()).collect();
+            deps.sort();
+            rdeps.sort();
+            (node.id(), (deps, rdeps))
+        })
+        .collect()
+}
+
+/// Node whose id is `node_id`. Aliases are stored under another key, like `Mount:<id>` for the
+/// mount of a container, so the values are searched when the key does not match.
+pub fn find_node(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, node_id: &str) -> Option<Rc<RefCell<dyn Node>>> {
+    match graph.get(node_id) {
+        Some(node) if node.borrow().id() == node_id => Some(Rc::clone(node)),
+        _ => graph.values().find(|node| node.borrow().id() == node_id).map(Rc::clone),
+    }
+}
+
+pub struct DanglingCategory {
+    pub desc: &'static str,
+    pub node_type: &'static str,
+    /// False for the tags: one nothing depends on is what the user keeps, not space to reclaim
+    pub reclaimable: bool,
+}
+
+/// Categories whose nodes can be dangling, listed by the UI and the doctor and removed by the cleanup.
+/// They go from the top of the graph down, so the nodes the cleanup releases in one category
+/// are picked up by the following ones.
+pub static DANGLING_CATEGORIES: &[DanglingCategory] = &[
+    DanglingCategory { desc: "ImageContent", node_type: "ImageContent", reclaimable: true },
+    DanglingCategory { desc: "Mount", node_type: "Mount", reclaimable: true },
+    DanglingCategory { desc: "ImageLayer", node_type: "ImageLayer", reclaimable: true },
+    DanglingCategory { desc: "LayerDiffId", node_type: "LayerDiffId", reclaimable: true },
+    DanglingCategory { desc: "MetadataDiffId", node_type: "MetadataDiffId", reclaimable: true },
+    DanglingCategory { desc: "Overlay2", node_type: "Overlay2", reclaimable: true },
+    DanglingCategory { desc: "ShortLink", node_type: "ShortLink", reclaimable: true },
+    DanglingCategory { desc: "ContentBlob", node_type: "ContentBlob", reclaimable: true },
+    DanglingCategory { desc: "Snapshot", node_type: "Snapshot", reclaimable: true },
+    DanglingCategory { desc: "Images", node_type: "ImageRepo", reclaimable: false },
+];
+
+fn unused(node: &dyn Node) -> bool {
+    node.rdeps().is_empty() && !node.in_use()
+}
+
+/// Whether the node is space to reclaim, as counted by the doctor and removed by the cleanup
+pub fn is_dangling(node: &dyn Node) -> bool {
+    let id = node.id();
+    let node_type = id.split(':').next().unwrap_or("Unknown");
+    DANGLING_CATEGORIES.iter().any(|category| category.reclaimable && category.node_type == node_type) && unused(node)
+}
+
+/// Nodes of the category nothing uses, the tags included
+pub fn dangling_nodes(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, category: &str) -> Vec<Rc<RefCell<dyn Node>>> {
+    let mut nodes: Vec<Rc<RefCell<dyn Node>>> = classify_layers(graph)
+        .remove(category)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|node| unused(&*node.borrow()))
+        .collect();
+    nodes.sort_by_key(|node| node.borrow().id());
+    nodes
+}
+
+/// Shortest path from `node` up through its rdeps to each node nothing depends on, like an ImageRepo or a Container.
+/// Each path starts with `node` itself. The rdeps are walked breadth first, each node once, so a deep
+/// overlay chain whose `lower` files name every layer below does not explode into every possible path.
+pub fn root_paths(node: &Rc<RefCell<dyn Node>>) -> Vec<Vec<Rc<RefCell<dyn Node>>>> {
+    let mut reached: Vec<Rc<RefCell<dyn Node>>> = vec![Rc::clone(node)];
+    // index of the node each one was first reached from
+    let mut parents: Vec<Option<usize>> = vec![None];
+    let mut ids = HashSet::from([node.borrow().id()]);
+    let mut paths = Vec::new();
+    let mut index = 0;
+    while index < reached.len() {
+        let current = Rc::clone(&reached[index]);
+        let mut rdeps: Vec<Rc<RefCell<dyn Node>>> = current.borrow().rdeps().iter().map(Rc::clone).collect();
+        rdeps.sort_by_key(|rdep| rdep.borrow().id());
+        if rdeps.is_empty() && index > 0 {
+            let mut path = Vec::new();
+            let mut step = Some(index);
+            while let Some(at) = step {
+                path.push(Rc::clone(&reached[at]));
+                step = parents[at];
             }
-            if !visited.contains(&current.borrow().id()) {
-                visited.insert(current.borrow().id());
-                result.push(Rc::clone(&current));
+            path.reverse();
+            paths.push(path);
+        }
+        for rdep in rdeps {
+            if ids.insert(rdep.borrow().id()) {
+                reached.push(rdep);
+                parents.push(Some(index));
             }
         }
-    } else {
-    
-        let node = graph.get(node_id).unwrap();
-        result.push(Rc::clone(node));
+        index += 1;
     }
-    Ok(result)
+    paths
 }
 
-pub fn remove_node(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, node: Rc<RefCell<dyn Node>>, recursive: bool) -> Result<()> {
-    if recursive {
-        // dfs, deletion on pre-order, to exit on 1st error, but only mess with a single branch in case of error
-        let mut visited = HashSet::new();
-        let mut stack = VecDeque::new();
+/// What keeps `node` alive, as a tree of its rdeps up to the roots, one line per node.
+/// A node reached again through another path is not expanded a second time.
+pub fn explain_tree(node: &Rc<RefCell<dyn Node>>) -> Vec<String> {
+    let mut lines = vec![node.borrow().label()];
+    let mut expanded = HashSet::new();
+    expanded.insert(node.borrow().id());
+    explain_rdeps(node, "", &mut expanded, &mut lines);
+    lines
+}
 
-        stack.push_back(node);
-    
-        while let Some(node) = stack.pop_back() {
-            if visited.contains(&node.borrow().id()) {
-                continue;
-            }
+fn explain_rdeps(node: &Rc<RefCell<dyn Node>>, prefix: &str, expanded: &mut HashSet<String>, lines: &mut Vec<String>) {
+    let mut rdeps: Vec<Rc<RefCell<dyn Node>>> = node.borrow().rdeps().iter().map(Rc::clone).collect();
+    rdeps.sort_by_key(|rdep| rdep.borrow().id());
+    for (index, rdep) in rdeps.iter().enumerate() {
+        let last = index + 1 == rdeps.len();
+        let rdep_id = rdep.borrow().id();
+        let rdep_label = rdep.borrow().label();
+        let (branch, indent) = if last { ("└── ", "    ") } else { ("├── ", "│   ") };
+        if expanded.insert(rdep_id.clone()) {
+            let root = if rdep.borrow().rdeps().is_empty() { " [root]" } else { "" };
+            lines.push(format!("{}{}{}{}", prefix, branch, rdep_label, root));
+            explain_rdeps(rdep, &format!("{}{}", prefix, indent), expanded, lines);
+        } else if !rdep.borrow().rdeps().is_empty() {
+            lines.push(format!("{}{}{} (see above)", prefix, branch, rdep_label));
+        } else {
+            lines.push(format!("{}{}{} [root]", prefix, branch, rdep_label));
+        }
+    }
+}
+
+pub struct DeletionStep {
+    pub node: Rc<RefCell<dyn Node>>,
+    pub node_id: String,
+    pub paths: Vec<PathBuf>,
+    pub bytes: u64,
+    /// Root of the plan this step was reached from
+    pub root_id: String,
+}
 
-            visited.insert(node.borrow().id());
+/// Ordered list of nodes to delete, computed once from the graph.
+/// A node always comes after all of its rdeps, so the plan can be executed front to back.
+pub struct DeletionPlan {
+    pub root_ids: Vec<String>,
+    pub recursive: bool,
+    pub steps: Vec<DeletionStep>,
+}
 
-            let result = node.borrow_mut().delete();
-            match result {
-                Ok(_) => {
-                    graph.remove(&node.borrow().id());
+impl DeletionPlan {
+    pub fn new(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, node_id: &str, recursive: bool) -> Result<DeletionPlan> {
+        Self::for_roots(graph, &[node_id.to_string()], recursive)
+    }
 
-                    // Push neighbors onto the stack in reverse order
-                    // This ensures we visit them in the original order when popping
-                    for dep in node.borrow().deps().iter().rev() {
-                        let dep_id = node.borrow().id();
+    /// One plan for several roots, like all the dangling nodes of a category.
+    /// A dep shared by several roots is planned once, after all of them.
+    pub fn for_roots(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, root_ids: &[String], recursive: bool) -> Result<DeletionPlan> {
+        let mut planned = HashSet::new();
+        let mut nodes = Vec::new();
+        let mut queue = VecDeque::new();
 
-                        dep.borrow_mut().rdeps_mut().retain(|node| node.borrow().id() != dep_id);
+        for root_id in root_ids {
+            let root = match find_node(graph, root_id) {
+                Some(root) => root,
+                None => anyhow::bail!("Node {} not found", root_id),
+            };
+            if planned.insert(root.borrow().id()) {
+                nodes.push((Rc::clone(&root), root_id.clone()));
+                queue.push_back((root, root_id.clone()));
+            }
+        }
 
-                        if dep.borrow().rdeps().len() == 0 {
-                            stack.push_back(Rc::clone(&dep));
-                        }
+        // a dep is only deleted once every node depending on it is part of the plan
+        if recursive {
+            while let Some((current, root_id)) = queue.pop_front() {
+                for dep in current.borrow().deps() {
+                    let dep_id = dep.borrow().id();
+                    if planned.contains(&dep_id) {
+                        continue;
+                    }
+                    if dep.borrow().rdeps().iter().all(|rdep| planned.contains(&rdep.borrow().id())) {
+                        planned.insert(dep_id);
+                        nodes.push((Rc::clone(dep), root_id.clone()));
+                        queue.push_back((Rc::clone(dep), root_id.clone()));
                     }
-                    node.borrow_mut().deps_mut().clear();                }
-                Err(e) => {
-                    eprintln!("error removing {}", &node.borrow().id());
-                    return Err(e)
                 }
             }
         }
-    } else {
-        match node.borrow().delete() {
-            Ok(_) => {
-                graph.remove(&node.borrow().id());
-                for dep in node.borrow().deps() {
-                    dep.borrow_mut().rdeps_mut().retain(|rdep| !Rc::ptr_eq(rdep, &node));
-                }
-                for rdep in node.borrow().rdeps() {
-                    rdep.borrow_mut().deps_mut().retain(|dep| !Rc::ptr_eq(dep, &node));
+
+        // sizes share the same inode set, so bytes of the steps add up to what is reclaimed
+        let mut seen = HashSet::new();
+        let steps = nodes.into_iter()
+            .map(|(node, root_id)| {
+                let (node_id, paths, bytes) = {
+                    let node = node.borrow();
+                    (node.id(), node.paths(), node.size(&mut seen))
+                };
+                DeletionStep { node, node_id, paths, bytes, root_id }
+            })
+            .collect();
+
+        Ok(DeletionPlan {
+            root_ids: root_ids.to_vec(),
+            recursive,
+            steps,
+        })
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.steps.iter().map(|step| step.bytes).sum()
+    }
+}
+
+pub struct DeleteOptions {
+    pub base_path: PathBuf,
+    /// Root of containerd with the containerd image store, its daemon must be stopped too
+    pub containerd_path: Option<PathBuf>,
+    /// Delete even if the docker daemon looks alive
+    pub force: bool,
+    pub remover: Remover,
+    pub journal: Journal,
+}
+
+/// Fails if the plan cannot be executed at all: the docker daemon is alive, or a node is mounted
+pub fn check_plan(plan: &DeletionPlan, options: &DeleteOptions) -> Result<()> {
+    ensure_daemon_stopped(&options.base_path, options.containerd_path.as_deref(), options.force)?;
+
+    for step in &plan.steps {
+        if let Some(reason) = step.node.borrow().in_use_reason() {
+            anyhow::bail!("{} is in use: {}", step.node_id, reason);
+        }
+    }
+    Ok(())
+}
+
+/// Executes the plan in order, stopping at the first error.
+/// The graph is updated after each deleted node, so a failure leaves it consistent with the disk.
+/// Returns the warnings of the deleted nodes, see `remove_step`.
+pub fn remove_node(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, plan: &DeletionPlan, options: &DeleteOptions) -> Result<Vec<String>> {
+    // checked before deleting anything, so a plan with a mounted node is left untouched
+    check_plan(plan, options)?;
+
+    let mut warnings = Vec::new();
+    for index in 0..plan.steps.len() {
+        warnings.extend(remove_step(graph, plan, index, options)?);
+    }
+
+    Ok(warnings)
+}
+
+/// Deletes the node of one step and removes it from the graph, `check_plan` is expected to have passed.
+/// A step whose rdeps were not deleted, because an earlier step failed, is refused.
+/// The node is deleted even if its journal entry cannot be written then, this is returned as a warning.
+pub fn remove_step(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, plan: &DeletionPlan, index: usize, options: &DeleteOptions) -> Result<Option<String>> {
+    let step = &plan.steps[index];
+    let node = &step.node;
+    // missing nodes are stored under the id of what they stand for, so look them up by pointer
+    if !graph.values().any(|current| Rc::ptr_eq(current, node)) {
+        anyhow::bail!("Deletion plan is stale: {} is no longer in the graph", step.node_id);
+    }
+    // only the roots may still have rdeps, the others were deleted by the previous steps
+    if !plan.root_ids.contains(&step.node_id) && !node.borrow().rdeps().is_empty() {
+        anyhow::bail!("Deletion plan is stale: {} is still used", step.node_id);
+    }
+
+    // a journal that cannot be written is found before anything is deleted
+    options.journal.check()?;
+    node.borrow().delete(&options.remover)?;
+
+    // also drops the aliases, like LayerDiffId:<diff> for image layers
+    graph.retain(|_, other| !Rc::ptr_eq(other, node));
+    for dep in node.borrow().deps() {
+        dep.borrow_mut().rdeps_mut().retain(|rdep| !Rc::ptr_eq(rdep, node));
+    }
+    for rdep in node.borrow().rdeps() {
+        rdep.borrow_mut().deps_mut().retain(|dep| !Rc::ptr_eq(dep, node));
+    }
+    node.borrow_mut().deps_mut().clear();
+    node.borrow_mut().rdeps_mut().clear();
+
+    let recursive_root = if plan.recursive { Some(step.root_id.as_str()) } else { None };
+    let entry = options.journal.entry(&step.node_id, &step.paths, step.bytes, recursive_root, options.remover.quarantine_root());
+    Ok(options.journal.append(&entry).err().map(|e| format!("{} deleted but not journaled: {:#}", step.node_id, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{analyze, image_store, untagged_images, DIFF_IDS, IMAGE_ID, SECOND_IMAGE_ID};
+    use crate::image::{chain_ids, ImageContentNode, ImageLayerNode, ImageRepoNode};
+    use crate::node::StaticId;
+
+    /// Index of each planned node, the steps must list every node before the nodes it depends on
+    fn assert_rdeps_first(plan: &DeletionPlan) {
+        let index: HashMap<String, usize> = plan.steps.iter().enumerate().map(|(index, step)| (step.node_id.clone(), index)).collect();
+        for (step_index, step) in plan.steps.iter().enumerate() {
+            for rdep in step.node.borrow().rdeps() {
+                if let Some(rdep_index) = index.get(&rdep.borrow().id()) {
+                    assert!(*rdep_index < step_index, "{} is planned before its rdep {}", step.node_id, rdep.borrow().id());
                 }
             }
-            Err(e) => {
-                eprintln!("error removing {}", &node.borrow().id());
-                return Err(e)
-            }
-        };
+        }
+        assert_eq!(index.len(), plan.steps.len(), "a node is planned twice");
     }
 
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn recursive_plan_lists_rdeps_before_deps() {
+        let dir = image_store();
+        let graph = analyze(dir.path());
+        let plan = DeletionPlan::new(&graph, &ImageRepoNode::static_id("app:latest"), true).unwrap();
+        assert_rdeps_first(&plan);
+        // the tag, the image, three layers with their overlay2 dir and short link
+        assert_eq!(plan.steps.len(), 11);
+        assert_eq!(plan.steps[0].node_id, ImageRepoNode::static_id("app:latest"));
+
+        let plan = DeletionPlan::new(&graph, &ImageRepoNode::static_id("app:latest"), false).unwrap();
+        assert_eq!(plan.steps.len(), 1);
+    }
+
+    #[test]
+    fn dep_shared_by_roots_is_planned_once_after_all_of_them() {
+        let dir = untagged_images();
+        let graph = analyze(dir.path());
+        let chain_ids = chain_ids(&DIFF_IDS);
+        let shared_id = ImageLayerNode::static_id(&chain_ids[1]);
+        let first_id = ImageContentNode::static_id(IMAGE_ID);
+        let second_id = ImageContentNode::static_id(SECOND_IMAGE_ID);
+
+        // alone, the second image leaves its layers to the first one
+        let plan = DeletionPlan::new(&graph, &second_id, true).unwrap();
+        assert_eq!(plan.steps.len(), 1);
+
+        let plan = DeletionPlan::for_roots(&graph, &[second_id.clone(), first_id.clone()], true).unwrap();
+        assert_rdeps_first(&plan);
+        let planned: Vec<&str> = plan.steps.iter().map(|step| step.node_id.as_str()).collect();
+        let position = |id: &str| planned.iter().position(|planned_id| *planned_id == id).unwrap();
+        assert!(position(&shared_id) > position(&second_id));
+        assert!(position(&shared_id) > position(&ImageLayerNode::static_id(&chain_ids[2])));
+        assert!(planned.contains(&ImageLayerNode::static_id(&chain_ids[0]).as_str()));
+        // the shared layer is released by the first image, the root it is recorded under
+        let shared = plan.steps.iter().find(|step| step.node_id == shared_id).unwrap();
+        assert_eq!(shared.root_id, first_id);
+    }
+}
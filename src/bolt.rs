@@ -0,0 +1,317 @@
+use std::fs;
+use std::path::Path;
+use anyhow::{Result, bail};
+
+// Read-only access to the bbolt files of containerd, only what is needed to list buckets and values.
+// Layout from go.etcd.io/bbolt, all integers little endian.
+const MAGIC: u32 = 0xED0C_DAED;
+/// Where the meta checksum is, it covers the meta fields before it
+const META_CHECKSUM_OFFSET: usize = 56;
+/// Page size bbolt assumes when the first meta page is unreadable, the OS one
+const DEFAULT_PAGE_SIZE: usize = 4096;
+const PAGE_HEADER_SIZE: usize = 16;
+const ELEMENT_SIZE: usize = 16;
+const BRANCH_PAGE: u16 = 0x01;
+const LEAF_PAGE: u16 = 0x02;
+const BUCKET_LEAF_FLAG: u32 = 0x01;
+/// Deeper trees mean a loop in a corrupt file
+const MAX_DEPTH: usize = 64;
+
+pub struct BoltDb {
+    data: Vec<u8>,
+    page_size: usize,
+    root: u64,
+}
+
+/// A bucket is either stored in its own pages, or inline in the value of its parent
+#[derive(Clone, Copy)]
+pub struct Bucket<'a> {
+    db: &'a BoltDb,
+    root: u64,
+    inline: Option<&'a [u8]>,
+}
+
+pub enum Entry<'a> {
+    Value(&'a [u8]),
+    Bucket(Bucket<'a>),
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    match data.get(offset..offset + 2) {
+        Some(bytes) => Ok(u16::from_le_bytes(bytes.try_into().unwrap())),
+        None => bail!("offset {} out of the page", offset),
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    match data.get(offset..offset + 4) {
+        Some(bytes) => Ok(u32::from_le_bytes(bytes.try_into().unwrap())),
+        None => bail!("offset {} out of the page", offset),
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    match data.get(offset..offset + 8) {
+        Some(bytes) => Ok(u64::from_le_bytes(bytes.try_into().unwrap())),
+        None => bail!("offset {} out of the page", offset),
+    }
+}
+
+fn read_bytes(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    match data.get(offset..offset + len) {
+        Some(bytes) => Ok(bytes),
+        None => bail!("{} bytes at offset {} out of the page", len, offset),
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Meta {
+    page_size: usize,
+    root: u64,
+    txid: u64,
+}
+
+/// FNV-1a 64 bits, the hash bbolt uses for the meta checksum
+fn fnv64a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The meta at `offset`, None unless its magic and checksum are right
+fn read_meta(data: &[u8], offset: usize) -> Option<Meta> {
+    if read_u32(data, offset).ok()? != MAGIC {
+        return None;
+    }
+    let fields = read_bytes(data, offset, META_CHECKSUM_OFFSET).ok()?;
+    if read_u64(data, offset + META_CHECKSUM_OFFSET).ok()? != fnv64a(fields) {
+        return None;
+    }
+    Some(Meta {
+        page_size: read_u32(data, offset + 8).ok()? as usize,
+        root: read_u64(data, offset + 16).ok()?,
+        txid: read_u64(data, offset + 48).ok()?,
+    })
+}
+
+impl BoltDb {
+    pub fn open(path: &Path) -> Result<BoltDb> {
+        BoltDb::from_bytes(fs::read(path)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<BoltDb> {
+        // two meta pages are written alternately, the valid one with the latest transaction wins,
+        // a torn first one leaves the page size unknown so the default is assumed for the second
+        let first = read_meta(&data, PAGE_HEADER_SIZE);
+        let page_size = first.map_or(DEFAULT_PAGE_SIZE, |meta| meta.page_size);
+        let second = read_meta(&data, page_size + PAGE_HEADER_SIZE);
+        let latest = [first, second].into_iter().flatten().max_by_key(|meta| meta.txid);
+        match latest {
+            Some(meta) if meta.page_size >= PAGE_HEADER_SIZE => Ok(BoltDb { data, page_size: meta.page_size, root: meta.root }),
+            _ => bail!("not a bolt database"),
+        }
+    }
+
+    pub fn root(&self) -> Bucket<'_> {
+        Bucket { db: self, root: self.root, inline: None }
+    }
+
+    fn page(&self, id: u64) -> Result<&[u8]> {
+        let offset = match usize::try_from(id).ok().and_then(|id| id.checked_mul(self.page_size)) {
+            Some(offset) => offset,
+            None => bail!("page {} out of the file", id),
+        };
+        match self.data.get(offset..) {
+            Some(page) if !page.is_empty() => Ok(page),
+            _ => bail!("page {} out of the file", id),
+        }
+    }
+}
+
+impl<'a> Bucket<'a> {
+    /// Keys and values or sub-buckets, in key order
+    pub fn entries(&self) -> Result<Vec<(&'a [u8], Entry<'a>)>> {
+        let mut entries = Vec::new();
+        match self.inline {
+            Some(page) => self.walk(page, 0, &mut entries)?,
+            None => self.walk(self.db.page(self.root)?, 0, &mut entries)?,
+        }
+        Ok(entries)
+    }
+
+    // a page that cannot be parsed is an error, not an empty bucket: what containerd records
+    // must not be taken for absent
+    pub fn get(&self, key: &str) -> Result<Option<&'a [u8]>> {
+        Ok(self.entries()?.into_iter().find_map(|(entry_key, entry)| match entry {
+            Entry::Value(value) if entry_key == key.as_bytes() => Some(value),
+            _ => None,
+        }))
+    }
+
+    pub fn bucket(&self, name: &str) -> Result<Option<Bucket<'a>>> {
+        Ok(self.entries()?.into_iter().find_map(|(entry_key, entry)| match entry {
+            Entry::Bucket(bucket) if entry_key == name.as_bytes() => Some(bucket),
+            _ => None,
+        }))
+    }
+
+    /// Sub-buckets with their names
+    pub fn buckets(&self) -> Result<Vec<(String, Bucket<'a>)>> {
+        Ok(self.entries()?.into_iter()
+            .filter_map(|(key, entry)| match entry {
+                Entry::Bucket(bucket) => Some((String::from_utf8_lossy(key).into_owned(), bucket)),
+                Entry::Value(_) => None,
+            })
+            .collect())
+    }
+
+    fn walk(&self, page: &'a [u8], depth: usize, entries: &mut Vec<(&'a [u8], Entry<'a>)>) -> Result<()> {
+        if depth > MAX_DEPTH {
+            bail!("bolt tree deeper than {}", MAX_DEPTH);
+        }
+        let flags = read_u16(page, 8)?;
+        let count = read_u16(page, 10)? as usize;
+        for index in 0..count {
+            // element positions are relative to the element itself
+            let element = PAGE_HEADER_SIZE + index * ELEMENT_SIZE;
+            if flags & BRANCH_PAGE != 0 {
+                let child = read_u64(page, element + 8)?;
+                self.walk(self.db.page(child)?, depth + 1, entries)?;
+            } else if flags & LEAF_PAGE != 0 {
+                let element_flags = read_u32(page, element)?;
+                let pos = element + read_u32(page, element + 4)? as usize;
+                let key_size = read_u32(page, element + 8)? as usize;
+                let value_size = read_u32(page, element + 12)? as usize;
+                let key = read_bytes(page, pos, key_size)?;
+                let value = read_bytes(page, pos + key_size, value_size)?;
+                if element_flags & BUCKET_LEAF_FLAG != 0 {
+                    let root = read_u64(value, 0)?;
+                    let inline = if root == 0 { Some(read_bytes(value, 16, value_size.saturating_sub(16))?) } else { None };
+                    entries.push((key, Entry::Bucket(Bucket { db: self.db, root, inline })));
+                } else {
+                    entries.push((key, Entry::Value(value)));
+                }
+            } else {
+                bail!("unexpected page flags {:#x}", flags);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Integers containerd stores with binary.PutUvarint
+pub fn read_uvarint(bytes: &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (index, byte) in bytes.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * index);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 4096;
+
+    pub(crate) enum TestEntry<'a> {
+        Value(&'a [u8]),
+        Bucket(Vec<(&'a str, TestEntry<'a>)>),
+        /// A bucket stored on its own page, none is written so it is past the end of the file
+        Page(u64),
+    }
+
+    /// Every bucket is written inline, in the value of its parent
+    fn leaf_page(entries: &[(&str, TestEntry)]) -> Vec<u8> {
+        let mut page = vec![0u8; PAGE_HEADER_SIZE];
+        page[8..10].copy_from_slice(&LEAF_PAGE.to_le_bytes());
+        page[10..12].copy_from_slice(&(entries.len() as u16).to_le_bytes());
+        let mut data = Vec::new();
+        let data_start = PAGE_HEADER_SIZE + entries.len() * ELEMENT_SIZE;
+        for (index, (key, entry)) in entries.iter().enumerate() {
+            let (flags, value) = match entry {
+                TestEntry::Value(value) => (0, value.to_vec()),
+                TestEntry::Bucket(children) => (BUCKET_LEAF_FLAG, [vec![0u8; 16], leaf_page(children)].concat()),
+                TestEntry::Page(root) => (BUCKET_LEAF_FLAG, [root.to_le_bytes(), [0u8; 8]].concat()),
+            };
+            let element = PAGE_HEADER_SIZE + index * ELEMENT_SIZE;
+            let pos = (data_start + data.len() - element) as u32;
+            for field in [flags, pos, key.len() as u32, value.len() as u32] {
+                page.extend_from_slice(&field.to_le_bytes());
+            }
+            data.extend_from_slice(key.as_bytes());
+            data.extend_from_slice(&value);
+        }
+        page.extend_from_slice(&data);
+        page
+    }
+
+    /// A database file with the given root bucket
+    pub(crate) fn database(root: &[(&str, TestEntry)]) -> Vec<u8> {
+        let root = leaf_page(root);
+        let mut data = vec![0u8; PAGE_SIZE * 3];
+        for (page, txid) in [(0usize, 1u64), (1, 2)] {
+            let meta = page * PAGE_SIZE + PAGE_HEADER_SIZE;
+            data[meta..meta + 4].copy_from_slice(&MAGIC.to_le_bytes());
+            data[meta + 8..meta + 12].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+            // the older meta page points to an empty page, it must not be used
+            let root_page = if txid == 2 { 3u64 } else { 2 };
+            data[meta + 16..meta + 24].copy_from_slice(&root_page.to_le_bytes());
+            data[meta + 48..meta + 56].copy_from_slice(&txid.to_le_bytes());
+            let checksum = fnv64a(&data[meta..meta + META_CHECKSUM_OFFSET]);
+            data[meta + META_CHECKSUM_OFFSET..meta + META_CHECKSUM_OFFSET + 8].copy_from_slice(&checksum.to_le_bytes());
+        }
+        data.extend_from_slice(&root);
+        data
+    }
+
+    #[test]
+    fn values_and_inline_buckets_are_read() {
+        let db = BoltDb::from_bytes(database(&[
+            ("name", TestEntry::Value(b"value")),
+            ("sub", TestEntry::Bucket(vec![("id", TestEntry::Value(&[0xac, 0x02]))])),
+        ])).unwrap();
+        let root = db.root();
+        assert_eq!(root.get("name").unwrap(), Some(&b"value"[..]));
+        let sub = root.bucket("sub").unwrap().unwrap();
+        assert_eq!(sub.get("id").unwrap().and_then(read_uvarint), Some(300));
+        assert_eq!(root.buckets().unwrap().len(), 1);
+        assert!(root.bucket("name").unwrap().is_none());
+    }
+
+    #[test]
+    fn unreadable_bucket_is_an_error() {
+        let db = BoltDb::from_bytes(database(&[
+            ("broken", TestEntry::Page(1000)),
+            ("sub", TestEntry::Bucket(Vec::new())),
+        ])).unwrap();
+        let broken = db.root().bucket("broken").unwrap().unwrap();
+        assert!(broken.buckets().is_err());
+        assert!(broken.bucket("sub").is_err());
+        assert!(broken.get("id").is_err());
+        assert!(db.root().bucket("sub").unwrap().unwrap().buckets().unwrap().is_empty());
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert!(BoltDb::from_bytes(vec![0u8; 100]).is_err());
+    }
+
+    #[test]
+    fn torn_meta_page_is_ignored() {
+        let mut data = database(&[("name", TestEntry::Value(b"value"))]);
+        // the latest transaction was not fully written, the older meta page is used
+        data[PAGE_SIZE + PAGE_HEADER_SIZE + 20] ^= 0xff;
+        let db = BoltDb::from_bytes(data).unwrap();
+        assert_eq!(db.root, 2);
+        assert!(db.root().entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn page_past_the_address_space_is_an_error() {
+        let db = BoltDb::from_bytes(database(&[])).unwrap();
+        assert!(db.page(u64::MAX).is_err());
+        assert!(db.page(u64::MAX / PAGE_SIZE as u64).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::cell::RefCell;
+use anyhow::Result;
+use crate::node::Node;
+use crate::analysis::{dangling_nodes, remove_node, DeleteOptions, DeletionPlan, DeletionStep, DANGLING_CATEGORIES};
+use crate::safety::ensure_daemon_stopped;
+use crate::size::format_size;
+
+// ImageRepo and Container are never dangling by themselves, they are what the user keeps
+fn cleanup_categories() -> impl Iterator<Item = &'static str> {
+    DANGLING_CATEGORIES.iter()
+        .filter(|category| category.reclaimable)
+        .map(|category| category.node_type)
+}
+
+fn print_step(action: &str, step: &DeletionStep) {
+    println!("{} {} ({})", action, step.node_id, format_size(step.bytes));
+    for path in &step.paths {
+        println!("    {}", path.display());
+    }
+}
+
+/// What `run_cleanup` deletes, without touching the graph. The real run removes the roots one by one,
+/// so a layer shared by two dangling images is released once the first one is gone: planning all
+/// the roots of a category together releases it the same way.
+fn dry_run_plans(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> Result<Vec<DeletionPlan>> {
+    let mut visited = HashSet::new();
+    let mut plans = Vec::new();
+    for category in cleanup_categories() {
+        let root_ids: Vec<String> = dangling_nodes(graph, category).iter()
+            .map(|root| root.borrow().id())
+            .filter(|root_id| !visited.contains(root_id))
+            .collect();
+        if root_ids.is_empty() {
+            continue;
+        }
+        let plan = DeletionPlan::for_roots(graph, &root_ids, true)?;
+        visited.extend(plan.steps.iter().map(|step| step.node_id.clone()));
+        plans.push(plan);
+    }
+    Ok(plans)
+}
+
+pub fn run_cleanup(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, dry_run: bool, options: &DeleteOptions) -> Result<()> {
+    if !dry_run {
+        ensure_daemon_stopped(&options.base_path, options.containerd_path.as_deref(), options.force)?;
+    }
+
+    if dry_run {
+        let mut count = 0;
+        let mut bytes = 0;
+        for plan in dry_run_plans(graph)? {
+            for step in &plan.steps {
+                print_step("would delete", step);
+                count += 1;
+                bytes += step.bytes;
+            }
+        }
+        println!("{} node(s) would be deleted, {} reclaimable", count, format_size(bytes));
+        return Ok(());
+    }
+
+    let mut visited = HashSet::new();
+    let mut deleted = 0;
+    let mut bytes = 0;
+    let mut failed = 0;
+
+    for category in cleanup_categories() {
+        for root in dangling_nodes(graph, category) {
+            let root_id = root.borrow().id();
+            if visited.contains(&root_id) {
+                continue;
+            }
+
+            let plan = DeletionPlan::new(graph, &root_id, true)?;
+            for step in &plan.steps {
+                visited.insert(step.node_id.clone());
+                bytes += step.bytes;
+                print_step("deleting", step);
+            }
+
+            match remove_node(graph, &plan, options) {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        eprintln!("warning: {}", warning);
+                    }
+                    deleted += plan.steps.len();
+                }
+                Err(e) => {
+                    eprintln!("error: {:#}", e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    println!("{} node(s) deleted, {} failure(s), {} planned", deleted, failed, format_size(bytes));
+
+    if failed > 0 {
+        anyhow::bail!("{} deletion(s) failed", failed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{analyze, delete_options, image_store, untagged_images};
+    use crate::overlay2::short_link_target;
+    use crate::trash::Remover;
+
+    #[test]
+    fn dry_run_plans_what_the_cleanup_deletes() {
+        let dir = untagged_images();
+        let mut graph = analyze(dir.path());
+        let mut planned: Vec<String> = dry_run_plans(&graph).unwrap().iter()
+            .flat_map(|plan| plan.steps.iter().map(|step| step.node_id.clone()))
+            .collect();
+        planned.sort();
+        assert!(planned.contains(&"Overlay2:cache0".to_string()));
+
+        let before: HashSet<String> = graph.values().map(|node| node.borrow().id()).collect();
+        run_cleanup(&mut graph, false, &delete_options(dir.path(), Remover::Delete)).unwrap();
+        let after: HashSet<String> = graph.values().map(|node| node.borrow().id()).collect();
+        let mut deleted: Vec<String> = before.difference(&after).cloned().collect();
+        deleted.sort();
+        assert_eq!(planned, deleted);
+    }
+
+    #[test]
+    fn cleanup_removes_every_reclaimable_category() {
+        let dir = image_store();
+        let orphan = dir.path().join("overlay2/l/ORPHAN");
+        std::os::unix::fs::symlink(short_link_target("gone"), &orphan).unwrap();
+        let mut graph = analyze(dir.path());
+        run_cleanup(&mut graph, false, &delete_options(dir.path(), Remover::Delete)).unwrap();
+
+        assert!(std::fs::symlink_metadata(&orphan).is_err());
+        assert!(!graph.contains_key("ShortLink:ORPHAN"));
+        // the tagged image keeps the rest
+        assert!(dir.path().join("overlay2/l/SHORT0").exists());
+        assert!(graph.contains_key("Overlay2:cache0"));
+    }
+}
@@ -1,17 +1,65 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::rc::Rc;
 use std::cell::RefCell;
 use anyhow::{Result, Context};
 use serde_json::Value;
-use crate::node::{Node, MissingNode};
+use crate::diagnostics::{read_dir_entries, record_corrupt};
+use crate::node::{link, link_or_missing, Node};
+use crate::trash::Remover;
 
 pub struct ContainerNode {
     container_id: String,
     deps: Vec<Rc<RefCell<dyn Node>>>,
     rdeps: Vec<Rc<RefCell<dyn Node>>>,
     path: PathBuf,
+    info: ContainerInfo,
+}
+
+/// What config.v2.json tells about a container, empty when it could not be read
+#[derive(Default)]
+struct ContainerInfo {
+    name: Option<String>,
+    state: Option<String>,
+    created: Option<String>,
+    image: Option<String>,
+}
+
+impl ContainerInfo {
+    fn from_config(config: &Value) -> ContainerInfo {
+        ContainerInfo {
+            name: config["Name"].as_str().map(|name| name.trim_start_matches('/').to_string()),
+            state: container_state(&config["State"]),
+            created: config["Created"].as_str().map(str::to_string),
+            image: config["Config"]["Image"].as_str().map(str::to_string),
+        }
+    }
+}
+
+/// Same names as `docker ps`. The status is not always stored, it is then rebuilt from the flags.
+fn container_state(state: &Value) -> Option<String> {
+    if let Some(status) = state["Status"].as_str() {
+        return Some(status.to_string());
+    }
+    if !state.is_object() {
+        return None;
+    }
+    let flag = |name: &str| state[name].as_bool().unwrap_or(false);
+    let status = if flag("Paused") {
+        "paused"
+    } else if flag("Restarting") {
+        "restarting"
+    } else if flag("Running") {
+        "running"
+    } else if flag("Dead") {
+        "dead"
+    } else if state["StartedAt"].as_str().is_none_or(|started| started.starts_with("0001-01-01")) {
+        "created"
+    } else {
+        "exited"
+    };
+    Some(status.to_string())
 }
 
 impl Node for ContainerNode {
@@ -34,8 +82,27 @@ impl Node for ContainerNode {
     fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
         &mut self.rdeps
     }
-    fn delete(&self) -> Result<()> {
-        fs::remove_dir_all(&self.path).context("Failed to remove container directory")
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    fn label(&self) -> String {
+        match (&self.info.name, &self.info.state) {
+            (Some(name), Some(state)) => format!("{} {} ({})", self.id(), name, state),
+            (Some(name), None) => format!("{} {}", self.id(), name),
+            _ => self.id(),
+        }
+    }
+
+    fn details(&self) -> Vec<String> {
+        let info = &self.info;
+        [("Name", &info.name), ("State", &info.state), ("Created", &info.created), ("Image", &info.image)].iter()
+            .filter_map(|(title, value)| value.as_ref().map(|value| format!("{}: {}", title, value)))
+            .collect()
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        remover.remove_dir_all(&self.path).context("Failed to remove container directory")
     }
 }
 
@@ -45,6 +112,7 @@ pub struct MountNode {
     deps: Vec<Rc<RefCell<dyn Node>>>,
     rdeps: Vec<Rc<RefCell<dyn Node>>>,
     path: PathBuf,
+    mounted: bool,
 }
 
 impl Node for MountNode {
@@ -68,75 +136,71 @@ impl Node for MountNode {
         &mut self.rdeps
     }
 
-    fn delete(&self) -> Result<()> {
-        fs::remove_dir_all(&self.path).context("Failed to remove mount directory")
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    fn in_use_reason(&self) -> Option<String> {
+        self.mounted.then(|| "the container rootfs is mounted".to_string())
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        remover.remove_dir_all(&self.path).context("Failed to remove mount directory")
     }
 }
 
-pub fn analyze_containers(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>) -> Result<()> {
+pub fn analyze_containers(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, mount_points: &HashSet<PathBuf>) -> Result<()> {
     let containers_path = base_path.join("containers");
     let mounts_path = base_path.join("image/overlay2/layerdb/mounts");
 
-    for entry in fs::read_dir(&mounts_path)? {
-        let entry = entry?;
+    for entry in read_dir_entries(graph, &mounts_path) {
         let mount_id = entry.file_name().to_string_lossy().into_owned();
         let mount_path = mounts_path.join(&mount_id);
 
+        // the container rootfs is the merged dir of the overlay named in mount-id
+        let mounted = match fs::read_to_string(mount_path.join("mount-id")) {
+            Ok(overlay_id) => mount_points.contains(&base_path.join("overlay2").join(overlay_id.trim()).join("merged")),
+            Err(_) => false,
+        };
         let mount_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MountNode {
             mount_id: mount_id.clone(),
             deps: Vec::new(),
             rdeps: Vec::new(),
             path: mount_path.clone(),
+            mounted,
         }));
 
         // Add dependencies on mount layers
         for overlay_file in ["init-id", "mount-id"] {
             let overlay_path = mount_path.join(overlay_file);
             if overlay_path.exists() {
-                let overlay_id = fs::read_to_string(overlay_path)?.trim().to_string();
-                let overlay_id = format!("Overlay2:{}", overlay_id);
-                if let Some(overlay_node) = graph.get(&overlay_id) {
-                    mount_node.borrow_mut().deps_mut().push(Rc::clone(overlay_node));
-                    overlay_node.borrow_mut().rdeps_mut().push(Rc::clone(&mount_node));
-                } else {
-                    let mut rdeps = Vec::new();
-                    rdeps.push(Rc::clone(&mount_node));
-                    let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
-                        id: overlay_id.clone(),
-                        deps: Vec::new(),
-                        rdeps,
-                    }));
-                    mount_node.borrow_mut().deps_mut().push(Rc::clone(&missing_node));
-                    graph.insert(overlay_id, missing_node);
-                }
+                let overlay_id = match fs::read_to_string(&overlay_path) {
+                    Ok(overlay_id) => overlay_id.trim().to_string(),
+                    Err(e) => {
+                        record_corrupt(graph, &overlay_path, &e, Some(&mount_node));
+                        continue;
+                    }
+                };
+                link_or_missing(graph, &mount_node, &format!("Overlay2:{}", overlay_id));
             }
         }
-        for layer_file in ["parent"] {
-            let layer_path = mount_path.join(layer_file);
-            if layer_path.exists() {
-                let layer_id = fs::read_to_string(layer_path)?.trim().to_string();
-                let layer_id = format!("ImageLayer:{}", &layer_id.trim_start_matches("sha256:"));
-                if let Some(layer_node) = graph.get(&layer_id) {
-                    mount_node.borrow_mut().deps_mut().push(Rc::clone(layer_node));
-                    layer_node.borrow_mut().rdeps_mut().push(Rc::clone(&mount_node));
-                } else {
-                    let mut rdeps = Vec::new();
-                    rdeps.push(Rc::clone(&mount_node));
-                    let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
-                        id: layer_id.clone(),
-                        deps: Vec::new(),
-                        rdeps,
-                    }));
-                    mount_node.borrow_mut().deps_mut().push(Rc::clone(&missing_node));
-                    graph.insert(layer_id, missing_node);
+        let layer_path = mount_path.join("parent");
+        let layer_id = match fs::read_to_string(&layer_path) {
+            Ok(layer_id) => Some(layer_id.trim().to_string()),
+            Err(e) => {
+                if layer_path.exists() {
+                    record_corrupt(graph, &layer_path, &e, Some(&mount_node));
                 }
+                None
             }
+        };
+        if let Some(layer_id) = layer_id {
+            link_or_missing(graph, &mount_node, &format!("ImageLayer:{}", &layer_id.trim_start_matches("sha256:")));
         }
         let mount_node_id = mount_node.borrow().id();
         graph.insert(mount_node_id, mount_node);
     }
-    for entry in fs::read_dir(&containers_path)? {
-        let entry = entry?;
+    for entry in read_dir_entries(graph, &containers_path) {
         let container_id = entry.file_name().to_string_lossy().into_owned();
 
         let container_node = Rc::new(RefCell::new(ContainerNode {
@@ -144,79 +208,45 @@ pub fn analyze_containers(base_path: &Path, graph: &mut HashMap<String, Rc<RefCe
             deps: Vec::new(),
             rdeps: Vec::new(),
             path: entry.path(),
+            info: ContainerInfo::default(),
         }));
 
         let config_path = entry.path().join("config.v2.json");
-        let config_content = fs::read_to_string(&config_path);
+        let config = fs::read_to_string(&config_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_str::<Value>(&content)?));
 
-        if let Err(_error) = config_content {
-          //  println!("no config.v2.json for {} {}", config_path.to_str().unwrap_or_default(), error);
-            graph.insert(format!("Container:{}", container_id), container_node);
-            continue;
-        }
-        let config_content = config_content.unwrap();
-        let config: Result<Value, serde_json::Error> = serde_json::from_str(&config_content);
-        
         match config {
             Ok(config) => {
+                container_node.borrow_mut().info = ContainerInfo::from_config(&config);
                 let image_id = config["Image"].as_str().unwrap_or("").trim_start_matches("sha256:");
     
-                // Add dependency on the image content
-                let image_content_id = format!("ImageContent:{}", image_id);
-                if let Some(image_node) = graph.get(&image_content_id) {
-                    container_node.borrow_mut().deps.push(Rc::clone(image_node));
-                    container_node.borrow_mut().rdeps.push(Rc::clone(&container_node) as Rc<RefCell<dyn Node + 'static>>);
-                } else {
-                    let mut rdeps: Vec<Rc<RefCell<dyn Node>>> = Vec::new();
-                    rdeps.push(Rc::clone(&container_node) as Rc<RefCell<dyn Node + 'static>>);
-                    let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
-                        id: image_content_id.clone(),
-                        deps: Vec::new(),
-                        rdeps,
-                    }));
-                    container_node.borrow_mut().deps.push(Rc::clone(&missing_node));
-                    graph.insert(image_content_id, missing_node);
-                }
-    
-                let mount_id = format!("Mount:{}", container_id);
-                match graph.get(&mount_id) {
-                    Some(node) => {
-                        container_node.borrow_mut().deps.push(node.clone());
-                        node.borrow_mut().rdeps_mut().push(Rc::clone(&container_node) as Rc<RefCell<dyn Node + 'static>>);
-                    }
-                    None => {
-                        let mut rdeps: Vec<Rc<RefCell<dyn Node>>> = Vec::new();
-                        rdeps.push(Rc::clone(&container_node) as Rc<RefCell<dyn Node + 'static>>);
-                        let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
-                            id: mount_id.clone(),
-                            deps: Vec::new(),
-                            rdeps,
-                        }));
-                        container_node.borrow_mut().deps.push(Rc::clone(&missing_node));
-                        let missing_node_id = missing_node.borrow().id();
-                        graph.insert(missing_node_id, missing_node);
-    
-                    }
-                }
+                // Add dependencies on the image content and the mount
+                let owner: Rc<RefCell<dyn Node>> = container_node.clone();
+                link_or_missing(graph, &owner, &format!("ImageContent:{}", image_id));
+                link_or_missing(graph, &owner, &format!("Mount:{}", container_id));
     
                 // Add dependencies on mount layers
                 let mount_path = mounts_path.join(&container_id);
                 for layer_file in ["init-id", "mount-id"] {
                     let layer_path = mount_path.join(layer_file);
-                    if layer_path.exists() {
-                        let layer_id = fs::read_to_string(layer_path)?.trim().to_string();
-                        let overlay_id = format!("Overlay2:{}", layer_id);
+                    // an unreadable file was already recorded on the mount node
+                    if let Ok(layer_id) = fs::read_to_string(layer_path) {
+                        let overlay_id = format!("Overlay2:{}", layer_id.trim());
                         if let Some(overlay_node) = graph.get(&overlay_id) {
-                            container_node.borrow_mut().deps.push(Rc::clone(overlay_node));
-                            overlay_node.borrow_mut().rdeps_mut().push(Rc::clone(&container_node) as Rc<RefCell<dyn Node + 'static>>);
-                        } else {
+                            link(&owner, overlay_node);
                         }
                     }
                 }
     
                 graph.insert(format!("Container:{}", container_id), container_node);    
             },
-            Err(error) => { println!("could not parse {} {}", config_path.to_str().unwrap_or_default(), error)}
+            Err(error) => {
+                // the container is kept, with the broken config as its dep
+                let owner: Rc<RefCell<dyn Node>> = container_node.clone();
+                record_corrupt(graph, &config_path, &error, Some(&owner));
+                graph.insert(format!("Container:{}", container_id), container_node);
+            }
         }
     }
 
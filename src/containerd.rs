@@ -0,0 +1,675 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::rc::Rc;
+use std::cell::RefCell;
+use anyhow::{Result, Context, bail};
+use serde_json::Value;
+use crate::bolt::{read_uvarint, BoltDb, Bucket};
+use crate::diagnostics::{read_dir_entries, record_corrupt};
+use crate::image::chain_ids;
+use crate::node::{link, link_or_missing, Node, StaticId};
+use crate::trash::Remover;
+
+// Layout of the containerd image store, used by Docker when `containerd-snapshotter` is enabled
+const CONTENT_PATH: &str = "io.containerd.content.v1.content/blobs/sha256";
+const SNAPSHOTS_PATH: &str = "io.containerd.snapshotter.v1.overlayfs/snapshots";
+const SNAPSHOTS_DB_PATH: &str = "io.containerd.snapshotter.v1.overlayfs/metadata.db";
+const META_DB_PATH: &str = "io.containerd.metadata.v1.bolt/meta.db";
+/// Manifests, indexes and configs are small, bigger blobs are layers and are not parsed
+const MAX_JSON_BLOB_SIZE: u64 = 4 * 1024 * 1024;
+
+fn recorded_error(id: &str) -> anyhow::Error {
+    anyhow::anyhow!("{} is recorded in the containerd metadata, remove it with docker or ctr", id)
+}
+
+fn unreadable_error(id: &str, db: &str) -> anyhow::Error {
+    anyhow::anyhow!("{} may be recorded in {}, which could not be read", id, db)
+}
+
+/// A file of the content store: layer tarball, image config, manifest or index
+pub struct ContentBlobNode {
+    digest: String,
+    deps: Vec<Rc<RefCell<dyn Node>>>,
+    rdeps: Vec<Rc<RefCell<dyn Node>>>,
+    path: PathBuf,
+    /// From the descriptors referencing the blob, or from the blob itself
+    media_type: Option<String>,
+    /// Listed in meta.db, removing the file alone would break containerd
+    recorded: bool,
+    /// meta.db could not be read, the blob is assumed to be recorded
+    db_unreadable: bool,
+}
+
+impl StaticId for ContentBlobNode {
+    fn static_id(digest: &str) -> String {
+        format!("ContentBlob:{}", digest.trim_start_matches("sha256:"))
+    }
+}
+
+impl Node for ContentBlobNode {
+    fn id(&self) -> String {
+        ContentBlobNode::static_id(&self.digest)
+    }
+
+    fn deps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.deps
+    }
+
+    fn deps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.deps
+    }
+
+    fn rdeps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.rdeps
+    }
+
+    fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.rdeps
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    fn label(&self) -> String {
+        match &self.media_type {
+            Some(media_type) => format!("{} ({})", self.id(), media_type),
+            None => self.id(),
+        }
+    }
+
+    // containerd garbage collects what meta.db records, it is not dangling even if no image uses it
+    fn in_use_reason(&self) -> Option<String> {
+        if self.recorded {
+            Some("recorded in meta.db".to_string())
+        } else if self.db_unreadable {
+            Some("meta.db could not be read, it may be recorded".to_string())
+        } else {
+            None
+        }
+    }
+
+    fn details(&self) -> Vec<String> {
+        let mut details = Vec::new();
+        if let Some(media_type) = &self.media_type {
+            details.push(format!("Media type: {}", media_type));
+        }
+        let recorded = match (self.recorded, self.db_unreadable) {
+            (true, _) => "yes",
+            (false, true) => "unknown, meta.db could not be read",
+            (false, false) => "no",
+        };
+        details.push(format!("Recorded in meta.db: {}", recorded));
+        details
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        if self.recorded {
+            return Err(recorded_error(&self.id()));
+        }
+        if self.db_unreadable {
+            return Err(unreadable_error(&self.id(), "meta.db"));
+        }
+        remover.remove_file(&self.path).context("Failed to remove content blob")
+    }
+}
+
+/// A directory of the overlayfs snapshotter, the containerd counterpart of an overlay2 layer
+pub struct SnapshotNode {
+    snapshot_id: String,
+    deps: Vec<Rc<RefCell<dyn Node>>>,
+    rdeps: Vec<Rc<RefCell<dyn Node>>>,
+    path: PathBuf,
+    /// Key in metadata.db, like `moby/12/sha256:<chain id>`, None for a directory it does not know
+    key: Option<String>,
+    kind: Option<String>,
+    /// metadata.db could not be read, the snapshot is assumed to be in it
+    db_unreadable: bool,
+}
+
+impl StaticId for SnapshotNode {
+    fn static_id(snapshot_id: &str) -> String {
+        format!("Snapshot:{}", snapshot_id)
+    }
+}
+
+impl Node for SnapshotNode {
+    fn id(&self) -> String {
+        SnapshotNode::static_id(&self.snapshot_id)
+    }
+
+    fn deps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.deps
+    }
+
+    fn deps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.deps
+    }
+
+    fn rdeps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.rdeps
+    }
+
+    fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.rdeps
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    // the mount of an active snapshot is somewhere else, it cannot be told from mountinfo
+    // whether it is still mounted, so it is assumed to be. The other snapshots of metadata.db
+    // are garbage collected by containerd, like the recorded blobs
+    fn in_use_reason(&self) -> Option<String> {
+        match (&self.key, self.kind.as_deref()) {
+            (Some(_), Some("active")) => Some("active snapshot, it may be mounted".to_string()),
+            (Some(_), _) => Some("recorded in metadata.db".to_string()),
+            (None, _) if self.db_unreadable => Some("metadata.db could not be read, it may be recorded".to_string()),
+            (None, _) => None,
+        }
+    }
+
+    fn label(&self) -> String {
+        match (&self.key, &self.kind) {
+            (Some(key), Some(kind)) => format!("{} {} ({})", self.id(), snapshot_name(key), kind),
+            (Some(key), None) => format!("{} {}", self.id(), snapshot_name(key)),
+            _ => self.id(),
+        }
+    }
+
+    fn details(&self) -> Vec<String> {
+        match (&self.key, &self.kind) {
+            (Some(key), kind) => vec![format!("Key: {}", key), format!("Kind: {}", kind.as_deref().unwrap_or("unknown"))],
+            _ if self.db_unreadable => vec!["metadata.db could not be read".to_string()],
+            _ => vec!["Not in metadata.db".to_string()],
+        }
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        if self.key.is_some() {
+            return Err(recorded_error(&self.id()));
+        }
+        if self.db_unreadable {
+            return Err(unreadable_error(&self.id(), "metadata.db"));
+        }
+        remover.remove_dir_all(&self.path).context("Failed to remove snapshot directory")
+    }
+}
+
+/// An image name of meta.db, pointing to its manifest or index
+pub struct ContainerdImageNode {
+    namespace: String,
+    name: String,
+    deps: Vec<Rc<RefCell<dyn Node>>>,
+    rdeps: Vec<Rc<RefCell<dyn Node>>>,
+}
+
+impl StaticId for ContainerdImageNode {
+    fn static_id(name: &str) -> String {
+        format!("ContainerdImage:{}", name)
+    }
+}
+
+impl Node for ContainerdImageNode {
+    fn id(&self) -> String {
+        ContainerdImageNode::static_id(&format!("{}/{}", self.namespace, self.name))
+    }
+
+    fn deps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.deps
+    }
+
+    fn deps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.deps
+    }
+
+    fn rdeps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.rdeps
+    }
+
+    fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.rdeps
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    fn delete(&self, _remover: &Remover) -> Result<()> {
+        bail!("{} only exists in meta.db, remove it with docker rmi", self.id())
+    }
+}
+
+/// Name given to the snapshot by its user, the key is `<namespace>/<sequence>/<name>`
+fn snapshot_name(key: &str) -> &str {
+    key.splitn(3, '/').nth(2).unwrap_or(key)
+}
+
+fn snapshot_kind(kind: &[u8]) -> Option<String> {
+    let kind = match kind.first()? {
+        1 => "view",
+        2 => "active",
+        3 => "committed",
+        _ => return None,
+    };
+    Some(kind.to_string())
+}
+
+/// The media type of a blob is taken from the first descriptor or blob giving one
+fn set_media_type(blob: &Rc<RefCell<ContentBlobNode>>, media_type: Option<String>) {
+    let mut blob = blob.borrow_mut();
+    if blob.media_type.is_none() {
+        blob.media_type = media_type;
+    }
+}
+
+fn read_json_blob(path: &Path) -> Option<Value> {
+    let metadata = fs::metadata(path).ok()?;
+    if metadata.len() > MAX_JSON_BLOB_SIZE {
+        return None;
+    }
+    let content = fs::read(path).ok()?;
+    if content.first() != Some(&b'{') {
+        return None;
+    }
+    serde_json::from_slice(&content).ok()
+}
+
+fn open_db(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, path: &Path) -> Option<BoltDb> {
+    match BoltDb::open(path) {
+        Ok(db) => Some(db),
+        Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => None,
+        Err(e) => {
+            record_corrupt(graph, path, &e, None);
+            None
+        }
+    }
+}
+
+/// Namespaces of meta.db, like `moby` for Docker
+fn namespaces<'a>(db: &'a BoltDb) -> Result<Vec<(String, Bucket<'a>)>> {
+    match db.root().bucket("v1")? {
+        Some(v1) => v1.buckets(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// A snapshot of metadata.db
+struct SnapshotRecord {
+    key: String,
+    snapshot_id: String,
+    kind: Option<String>,
+    parent_key: Option<String>,
+}
+
+fn snapshot_records(db: &BoltDb) -> Result<Vec<SnapshotRecord>> {
+    let snapshots = match db.root().bucket("v1")? {
+        Some(v1) => v1.bucket("snapshots")?,
+        None => None,
+    };
+    let mut records = Vec::new();
+    for (key, bucket) in snapshots.map(|snapshots| snapshots.buckets()).transpose()?.unwrap_or_default() {
+        let snapshot_id = match bucket.get("id")?.and_then(read_uvarint) {
+            Some(snapshot_id) => snapshot_id.to_string(),
+            None => continue,
+        };
+        records.push(SnapshotRecord {
+            key,
+            snapshot_id,
+            kind: bucket.get("kind")?.and_then(snapshot_kind),
+            parent_key: bucket.get("parent")?.map(|parent| String::from_utf8_lossy(parent).into_owned()),
+        });
+    }
+    Ok(records)
+}
+
+/// Digests of the blobs meta.db records, in any namespace
+fn recorded_blobs(db: &BoltDb) -> Result<HashSet<String>> {
+    let mut recorded = HashSet::new();
+    for (_, namespace) in namespaces(db)? {
+        let blobs = match namespace.bucket("content")? {
+            Some(content) => content.bucket("blob")?,
+            None => None,
+        };
+        if let Some(blobs) = blobs {
+            recorded.extend(blobs.buckets()?.into_iter().map(|(digest, _)| digest));
+        }
+    }
+    Ok(recorded)
+}
+
+/// Namespace, name and target digest of the images of meta.db
+fn image_targets(db: &BoltDb) -> Result<Vec<(String, String, Option<String>)>> {
+    let mut targets = Vec::new();
+    for (namespace, namespace_bucket) in namespaces(db)? {
+        let images = match namespace_bucket.bucket("images")? {
+            Some(images) => images.buckets()?,
+            None => Vec::new(),
+        };
+        for (name, image) in images {
+            let digest = match image.bucket("target")? {
+                Some(target) => target.get("digest")?.map(|digest| String::from_utf8_lossy(digest).into_owned()),
+                None => None,
+            };
+            targets.push((namespace.clone(), name, digest));
+        }
+    }
+    Ok(targets)
+}
+
+/// What the database at `path` records, None when it could not be opened or parsed, the error is recorded.
+/// The analysis fails closed: without the records, every node of that store is kept.
+fn read_records<T>(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, path: &Path, db: Option<&BoltDb>, read: impl Fn(&BoltDb) -> Result<T>) -> Option<T> {
+    match read(db?) {
+        Ok(records) => Some(records),
+        Err(e) => {
+            record_corrupt(graph, path, &e, None);
+            None
+        }
+    }
+}
+
+pub fn analyze_containerd(containerd_path: &Path, graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>) -> Result<()> {
+    let meta_db_path = containerd_path.join(META_DB_PATH);
+    let meta_db = open_db(graph, &meta_db_path);
+
+    // Step 1: Snapshots, from their directories, then linked to their parents using metadata.db
+    let snapshots_db_path = containerd_path.join(SNAPSHOTS_DB_PATH);
+    let snapshots_db = open_db(graph, &snapshots_db_path);
+    let records = read_records(graph, &snapshots_db_path, snapshots_db.as_ref(), snapshot_records);
+    let snapshots_path = containerd_path.join(SNAPSHOTS_PATH);
+    let mut snapshots: HashMap<String, Rc<RefCell<SnapshotNode>>> = HashMap::new();
+    for entry in read_dir_entries(graph, &snapshots_path) {
+        let snapshot_id = entry.file_name().to_string_lossy().into_owned();
+        snapshots.insert(snapshot_id.clone(), Rc::new(RefCell::new(SnapshotNode {
+            snapshot_id,
+            deps: Vec::new(),
+            rdeps: Vec::new(),
+            path: entry.path(),
+            key: None,
+            kind: None,
+            db_unreadable: records.is_none(),
+        })));
+    }
+    for snapshot in snapshots.values() {
+        let snapshot_node_id = snapshot.borrow().id();
+        graph.insert(snapshot_node_id, snapshot.clone());
+    }
+
+    // key -> snapshot id, and the parent key of each key
+    let mut snapshot_ids: HashMap<String, String> = HashMap::new();
+    let mut parents: Vec<(String, String)> = Vec::new();
+    for record in records.unwrap_or_default() {
+        if let Some(snapshot) = snapshots.get(&record.snapshot_id) {
+            let mut snapshot = snapshot.borrow_mut();
+            snapshot.key = Some(record.key.clone());
+            snapshot.kind = record.kind;
+        }
+        if let Some(parent_key) = record.parent_key {
+            parents.push((record.key.clone(), parent_key));
+        }
+        snapshot_ids.insert(record.key, record.snapshot_id);
+    }
+    for (key, parent_key) in &parents {
+        let node = match graph.get(&SnapshotNode::static_id(&snapshot_ids[key])).map(Rc::clone) {
+            Some(node) => node,
+            None => continue,
+        };
+        match snapshot_ids.get(parent_key) {
+            Some(parent_id) => link_or_missing(graph, &node, &SnapshotNode::static_id(parent_id)),
+            None => link_or_missing(graph, &node, &format!("Snapshot:{}", parent_key)),
+        }
+    }
+    // committed layers are named after their chain ID, containers after their id
+    let mut snapshots_by_name: HashMap<String, String> = HashMap::new();
+    for (key, snapshot_id) in &snapshot_ids {
+        snapshots_by_name.insert(snapshot_name(key).to_string(), SnapshotNode::static_id(snapshot_id));
+    }
+
+    // Step 2: Content blobs
+    let content_path = containerd_path.join(CONTENT_PATH);
+    let recorded = read_records(graph, &meta_db_path, meta_db.as_ref(), recorded_blobs);
+    let mut blobs: HashMap<String, Rc<RefCell<ContentBlobNode>>> = HashMap::new();
+    for entry in read_dir_entries(graph, &content_path) {
+        let digest = format!("sha256:{}", entry.file_name().to_string_lossy());
+        let blob = Rc::new(RefCell::new(ContentBlobNode {
+            recorded: recorded.as_ref().is_some_and(|recorded| recorded.contains(&digest)),
+            db_unreadable: recorded.is_none(),
+            digest,
+            deps: Vec::new(),
+            rdeps: Vec::new(),
+            path: entry.path(),
+            media_type: None,
+        }));
+        let blob_id = blob.borrow().id();
+        graph.insert(blob_id.clone(), blob.clone());
+        blobs.insert(blob_id, blob);
+    }
+
+    // Step 3: What the manifests, indexes and configs point to
+    let mut blob_ids: Vec<String> = blobs.keys().cloned().collect();
+    blob_ids.sort();
+    for blob_id in &blob_ids {
+        let blob = &blobs[blob_id];
+        let node: Rc<RefCell<dyn Node>> = blob.clone();
+        let content = match read_json_blob(&blob.borrow().path) {
+            Some(content) => content,
+            None => continue,
+        };
+        set_media_type(blob, content["mediaType"].as_str().map(str::to_string));
+
+        let descriptor = |descriptor: &Value| descriptor["digest"].as_str().map(|digest| {
+            (ContentBlobNode::static_id(digest), descriptor["mediaType"].as_str().map(str::to_string))
+        });
+        // an index lists the manifests of every platform, only the pulled ones are on disk
+        for (manifest_id, media_type) in content["manifests"].as_array().into_iter().flatten().filter_map(descriptor) {
+            if let Some(manifest) = blobs.get(&manifest_id) {
+                link(&node, &(manifest.clone() as Rc<RefCell<dyn Node>>));
+                set_media_type(manifest, media_type);
+            }
+        }
+        if let Some((config_id, media_type)) = descriptor(&content["config"]) {
+            if let Some(config) = blobs.get(&config_id) {
+                set_media_type(config, media_type);
+            }
+            link_or_missing(graph, &node, &config_id);
+        }
+        for (layer_id, media_type) in content["layers"].as_array().into_iter().flatten().filter_map(descriptor) {
+            if let Some(layer) = blobs.get(&layer_id) {
+                link(&node, &(layer.clone() as Rc<RefCell<dyn Node>>));
+                set_media_type(layer, media_type);
+            }
+        }
+
+        // an image config depends on the snapshot of its top layer, when it was unpacked
+        if let Some(diff_ids) = content["rootfs"]["diff_ids"].as_array() {
+            let diff_ids: Vec<&str> = diff_ids.iter().filter_map(Value::as_str).collect();
+            let top_chain_id = chain_ids(&diff_ids).pop();
+            let snapshot_id = top_chain_id.and_then(|chain_id| snapshots_by_name.get(&format!("sha256:{}", chain_id)));
+            if let Some(snapshot) = snapshot_id.and_then(|snapshot_id| graph.get(snapshot_id)).map(Rc::clone) {
+                link(&node, &snapshot);
+            }
+        }
+    }
+
+    // Step 4: Image names of meta.db
+    let images = match read_records(graph, &meta_db_path, meta_db.as_ref(), image_targets) {
+        Some(images) => images,
+        None => {
+            // an image that could not be read may use any blob
+            for blob in blobs.values() {
+                blob.borrow_mut().db_unreadable = true;
+            }
+            Vec::new()
+        }
+    };
+    for (namespace, name, digest) in images {
+        let node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(ContainerdImageNode {
+            namespace,
+            name,
+            deps: Vec::new(),
+            rdeps: Vec::new(),
+        }));
+        if let Some(digest) = digest {
+            let target_id = ContentBlobNode::static_id(&digest);
+            link_or_missing(graph, &node, &target_id);
+            // containers refer to the image by its target digest, and to their rw snapshot as their mount
+            if let Some(target) = graph.get(&target_id).map(Rc::clone) {
+                graph.insert(format!("ImageContent:{}", digest.trim_start_matches("sha256:")), target);
+            }
+        }
+        let node_id = node.borrow().id();
+        graph.insert(node_id, node);
+    }
+    for (name, snapshot_id) in &snapshots_by_name {
+        if !name.starts_with("sha256:") {
+            if let Some(snapshot) = graph.get(snapshot_id).map(Rc::clone) {
+                graph.insert(format!("Mount:{}", name), snapshot);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::dangling_nodes;
+    use crate::bolt::tests::{database, TestEntry};
+    use crate::fixtures::{dep_ids, write};
+    use crate::node::CorruptNode;
+    use sha2::{Digest, Sha256};
+
+    const CHAIN_ID: &str = "sha256:1111111111111111111111111111111111111111111111111111111111111111";
+
+    fn digest(content: &str) -> String {
+        format!("sha256:{:x}", Sha256::digest(content.as_bytes()))
+    }
+
+    /// An image made of one layer, unpacked, with a container on top, plus an orphan blob and snapshot dir,
+    /// and a blob and a snapshot no image uses but that containerd still records
+    fn fixture() -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let blob = |content: &str| {
+            let digest = digest(content);
+            write(&root.join(CONTENT_PATH).join(digest.trim_start_matches("sha256:")), content.as_bytes());
+            digest
+        };
+        let config = blob(&serde_json::json!({ "rootfs": { "type": "layers", "diff_ids": [CHAIN_ID] } }).to_string());
+        let layer = blob("layer tarball");
+        blob("orphan");
+        let unused = blob("recorded but unused");
+        let manifest = blob(&serde_json::json!({
+            "mediaType": "application/vnd.oci.image.manifest.v1+json",
+            "config": { "mediaType": "application/vnd.oci.image.config.v1+json", "digest": config },
+            "layers": [{ "mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": layer }],
+        }).to_string());
+        for snapshot_id in ["1", "2", "3", "9"] {
+            fs::create_dir_all(root.join(SNAPSHOTS_PATH).join(snapshot_id).join("fs")).unwrap();
+        }
+
+        let layer_key = format!("moby/1/{}", CHAIN_ID);
+        write(&root.join(SNAPSHOTS_DB_PATH), database(&[("v1", TestEntry::Bucket(vec![("snapshots", TestEntry::Bucket(vec![
+            (layer_key.as_str(), TestEntry::Bucket(vec![("id", TestEntry::Value(&[1])), ("kind", TestEntry::Value(&[3]))])),
+            ("moby/2/c1", TestEntry::Bucket(vec![
+                ("id", TestEntry::Value(&[2])),
+                ("kind", TestEntry::Value(&[2])),
+                ("parent", TestEntry::Value(layer_key.as_bytes())),
+            ])),
+            ("moby/3/sha256:unused", TestEntry::Bucket(vec![("id", TestEntry::Value(&[3])), ("kind", TestEntry::Value(&[3]))])),
+        ]))]))]));
+        let recorded: Vec<(&str, TestEntry)> = [&config, &layer, &manifest, &unused].iter()
+            .map(|digest| (digest.as_str(), TestEntry::Bucket(Vec::new())))
+            .collect();
+        write(&root.join(META_DB_PATH), database(&[("v1", TestEntry::Bucket(vec![("moby", TestEntry::Bucket(vec![
+            ("content", TestEntry::Bucket(vec![("blob", TestEntry::Bucket(recorded))])),
+            ("images", TestEntry::Bucket(vec![("app:latest", TestEntry::Bucket(vec![
+                ("target", TestEntry::Bucket(vec![("digest", TestEntry::Value(manifest.as_bytes()))])),
+            ]))])),
+        ]))]))]));
+
+        (dir, manifest)
+    }
+
+    #[test]
+    fn image_is_linked_down_to_its_snapshots() {
+        let (dir, manifest) = fixture();
+        let mut graph = HashMap::new();
+        analyze_containerd(dir.path(), &mut graph).unwrap();
+
+        let manifest_id = ContentBlobNode::static_id(&manifest);
+        assert_eq!(dep_ids(&graph, "ContainerdImage:moby/app:latest"), vec![manifest_id.clone()]);
+        let manifest_deps = dep_ids(&graph, &manifest_id);
+        assert_eq!(manifest_deps.len(), 2);
+        let config_id = manifest_deps.iter().find(|dep_id| dep_ids(&graph, dep_id).contains(&"Snapshot:1".to_string()));
+        assert!(config_id.is_some());
+        assert_eq!(dep_ids(&graph, "Snapshot:2"), vec!["Snapshot:1".to_string()]);
+
+        // what the classic container analyzer looks up
+        assert_eq!(graph.get(&format!("ImageContent:{}", manifest.trim_start_matches("sha256:"))).unwrap().borrow().id(), manifest_id);
+        assert_eq!(graph.get("Mount:c1").unwrap().borrow().id(), "Snapshot:2");
+
+        let dangling: Vec<String> = dangling_nodes(&graph, "ContentBlob").iter().map(|node| node.borrow().id()).collect();
+        assert_eq!(dangling, vec![ContentBlobNode::static_id(&digest("orphan"))]);
+        let dangling: Vec<String> = dangling_nodes(&graph, "Snapshot").iter().map(|node| node.borrow().id()).collect();
+        assert_eq!(dangling, vec!["Snapshot:9".to_string()]);
+        assert!(graph.get("Snapshot:3").unwrap().borrow().rdeps().is_empty());
+        assert!(graph.get(&ContentBlobNode::static_id(&digest("recorded but unused"))).unwrap().borrow().rdeps().is_empty());
+    }
+
+    #[test]
+    fn recorded_nodes_are_not_deleted() {
+        let (dir, _) = fixture();
+        let mut graph = HashMap::new();
+        analyze_containerd(dir.path(), &mut graph).unwrap();
+
+        let reason = |id: &str| graph.get(id).unwrap().borrow().in_use_reason();
+        assert_eq!(reason("Snapshot:1").as_deref(), Some("recorded in metadata.db"));
+        assert_eq!(reason("Snapshot:2").as_deref(), Some("active snapshot, it may be mounted"));
+        assert_eq!(reason("Snapshot:9"), None);
+        assert_eq!(reason(&ContentBlobNode::static_id(&digest("recorded but unused"))).as_deref(), Some("recorded in meta.db"));
+
+        assert!(graph.get("Snapshot:1").unwrap().borrow().delete(&Remover::Delete).is_err());
+        assert!(dir.path().join(SNAPSHOTS_PATH).join("1").exists());
+        graph.get("Snapshot:9").unwrap().borrow().delete(&Remover::Delete).unwrap();
+        assert!(!dir.path().join(SNAPSHOTS_PATH).join("9").exists());
+    }
+
+    #[test]
+    fn unreadable_databases_keep_every_node() {
+        let (dir, _) = fixture();
+        write(&dir.path().join(META_DB_PATH), "not a bolt database");
+        fs::remove_file(dir.path().join(SNAPSHOTS_DB_PATH)).unwrap();
+        let mut graph = HashMap::new();
+        analyze_containerd(dir.path(), &mut graph).unwrap();
+
+        assert!(graph.contains_key(&CorruptNode::static_id(&dir.path().join(META_DB_PATH).to_string_lossy())));
+        assert!(dangling_nodes(&graph, "ContentBlob").is_empty());
+        assert!(dangling_nodes(&graph, "Snapshot").is_empty());
+        let orphan = graph.get(&ContentBlobNode::static_id(&digest("orphan"))).unwrap();
+        assert_eq!(orphan.borrow().in_use_reason().as_deref(), Some("meta.db could not be read, it may be recorded"));
+        assert!(orphan.borrow().delete(&Remover::Delete).is_err());
+        assert!(graph.get("Snapshot:9").unwrap().borrow().delete(&Remover::Delete).is_err());
+        assert!(dir.path().join(SNAPSHOTS_PATH).join("9").exists());
+    }
+
+    #[test]
+    fn unparsable_bucket_keeps_every_blob() {
+        let (dir, _) = fixture();
+        write(&dir.path().join(META_DB_PATH), database(&[("v1", TestEntry::Bucket(vec![
+            ("moby", TestEntry::Bucket(vec![("content", TestEntry::Page(1000))])),
+        ]))]));
+        let mut graph = HashMap::new();
+        analyze_containerd(dir.path(), &mut graph).unwrap();
+
+        assert!(graph.contains_key(&CorruptNode::static_id(&dir.path().join(META_DB_PATH).to_string_lossy())));
+        assert!(dangling_nodes(&graph, "ContentBlob").is_empty());
+        // metadata.db is still read
+        let dangling: Vec<String> = dangling_nodes(&graph, "Snapshot").iter().map(|node| node.borrow().id()).collect();
+        assert_eq!(dangling, vec!["Snapshot:9".to_string()]);
+    }
+}
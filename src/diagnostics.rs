@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::node::{CorruptNode, Node, StaticId};
+
+/// Records an artifact that could not be read or parsed as a CorruptNode, so the scan can go on.
+/// `owner` is the node the artifact belongs to, it gets the CorruptNode as a dep.
+pub fn record_corrupt(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, path: &Path, error: &dyn Display, owner: Option<&Rc<RefCell<dyn Node>>>) {
+    let corrupt_id = CorruptNode::static_id(&path.to_string_lossy());
+    let corrupt_node = match graph.get(&corrupt_id) {
+        Some(corrupt_node) => Rc::clone(corrupt_node),
+        None => {
+            let corrupt_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(CorruptNode {
+                path: path.to_path_buf(),
+                error: error.to_string(),
+                deps: Vec::new(),
+                rdeps: Vec::new(),
+            }));
+            graph.insert(corrupt_id, Rc::clone(&corrupt_node));
+            corrupt_node
+        }
+    };
+
+    if let Some(owner) = owner {
+        owner.borrow_mut().deps_mut().push(Rc::clone(&corrupt_node));
+        corrupt_node.borrow_mut().rdeps_mut().push(Rc::clone(owner));
+    }
+}
+
+/// Entries of a directory. A directory that does not exist is empty, any other error is recorded.
+pub fn read_dir_entries(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, path: &Path) -> Vec<fs::DirEntry> {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            record_corrupt(graph, path, &e, None);
+            return Vec::new();
+        }
+    };
+
+    let mut result = Vec::new();
+    for entry in entries {
+        match entry {
+            Ok(entry) => result.push(entry),
+            Err(e) => record_corrupt(graph, path, &e, None),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{build_graph, Store};
+    use crate::fixtures::{image_store, write, DIFF_IDS};
+    use crate::image::{chain_ids, LAYERDB_PATH};
+
+    fn corrupt_rdeps(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, path: &Path) -> Vec<String> {
+        let corrupt = graph.get(&CorruptNode::static_id(&path.to_string_lossy())).unwrap().borrow();
+        corrupt.rdeps().iter().map(|rdep| rdep.borrow().id()).collect()
+    }
+
+    #[test]
+    fn scan_of_a_damaged_root_records_what_it_could_not_read() {
+        let dir = image_store();
+        let base = dir.path();
+        let config_path = base.join("containers/abc/config.v2.json");
+        write(&config_path, r#"{"ID": "abc", "Image": "sha256:"#);
+        let chain_id = &chain_ids(&DIFF_IDS)[1];
+        let cache_id_path = base.join(LAYERDB_PATH).join(chain_id).join("cache-id");
+        fs::remove_file(&cache_id_path).unwrap();
+        // a file where a directory is expected cannot be listed, even by root
+        let links_path = base.join("overlay2/l");
+        fs::remove_dir_all(&links_path).unwrap();
+        write(&links_path, "");
+
+        let graph = build_graph(base, &Store::Overlay2).unwrap();
+
+        assert_eq!(corrupt_rdeps(&graph, &config_path), vec!["Container:abc"]);
+        assert_eq!(corrupt_rdeps(&graph, &cache_id_path), vec![format!("ImageLayer:{}", chain_id)]);
+        assert!(corrupt_rdeps(&graph, &links_path).is_empty());
+        // the rest of the root is still scanned
+        assert!(graph.contains_key("Overlay2:cache0"));
+        assert!(graph.contains_key(&format!("ImageLayer:{}", chain_id)));
+        assert!(graph.values().any(|node| node.borrow().id().starts_with("ImageContent:")));
+    }
+}
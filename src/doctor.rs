@@ -0,0 +1,146 @@
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::cell::RefCell;
+use crate::analysis::{classify_layers, dangling_nodes, root_paths, DANGLING_CATEGORIES};
+use crate::node::Node;
+use crate::size::format_size;
+
+/// Overall health of the host, the exit code follows the monitoring plugin convention
+#[derive(Clone, Copy)]
+pub enum Verdict {
+    Healthy = 0,
+    /// Dangling nodes, space can be reclaimed
+    Warning = 1,
+    /// Missing, corrupt or incomplete nodes, docker itself may fail
+    Error = 2,
+}
+
+impl Verdict {
+    pub fn exit_code(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Prints the report and returns the verdict, nothing is modified
+pub fn run_doctor(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> Verdict {
+    let mut verdict = Verdict::Healthy;
+    let mut dangling_count = 0;
+    let mut dangling_bytes = 0;
+
+    println!("Dangling nodes");
+    let mut seen = HashSet::new();
+    for category in DANGLING_CATEGORIES.iter().filter(|category| category.reclaimable) {
+        let nodes = dangling_nodes(graph, category.node_type);
+        // the inode set is shared, so a file held by several categories is counted once
+        let bytes: u64 = nodes.iter().map(|node| node.borrow().size(&mut seen)).sum();
+        println!("    {}: {} node(s), {}", category.desc, nodes.len(), format_size(bytes));
+        dangling_count += nodes.len();
+        dangling_bytes += bytes;
+    }
+    if dangling_count > 0 {
+        verdict = Verdict::Warning;
+    }
+
+    let mut classified = classify_layers(graph);
+    let missing = classified.remove("MissingNode").unwrap_or_default();
+    let corrupt = classified.remove("Corrupt").unwrap_or_default();
+    // nodes that exist but that docker cannot use, like an overlay2 layer without committed
+    let incomplete: Vec<Rc<RefCell<dyn Node>>> = classified.into_values().flatten()
+        .filter(|node| !node.borrow().problems().is_empty())
+        .collect();
+    let mut broken = 0;
+    for (title, mut nodes) in [("Missing nodes", missing), ("Corrupt nodes", corrupt), ("Incomplete nodes", incomplete)] {
+        nodes.sort_by_key(|node| node.borrow().id());
+        println!("{}: {}", title, nodes.len());
+        for node in &nodes {
+            println!("    {}", node.borrow().id());
+            for line in node.borrow().details() {
+                println!("        {}", line);
+            }
+            for path in root_paths(node) {
+                let path: Vec<String> = path.iter().map(|node| node.borrow().id()).collect();
+                println!("        {}", path.join(" => rdep:"));
+            }
+        }
+        broken += nodes.len();
+    }
+    if broken > 0 {
+        verdict = Verdict::Error;
+    }
+
+    match verdict {
+        Verdict::Healthy => println!("Verdict: healthy"),
+        Verdict::Warning => println!("Verdict: warning, {} dangling node(s), {} reclaimable", dangling_count, format_size(dangling_bytes)),
+        Verdict::Error => println!("Verdict: error, {} missing, corrupt or incomplete node(s), {} dangling node(s)", broken, dangling_count),
+    }
+    verdict
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use crate::analysis::root_paths;
+    use crate::node::MissingNode;
+    use crate::overlay2::analyze_overlay2;
+
+    /// `depth` overlays, each `lower` naming every layer below as docker writes it, the bottom one naming a short link that is gone
+    fn overlay_chain(base: &Path, depth: usize) -> HashMap<String, Rc<RefCell<dyn Node>>> {
+        let mut lower = vec!["l/GONE".to_string()];
+        for index in 0..depth {
+            let path = base.join("overlay2").join(format!("layer{}", index));
+            fs::create_dir_all(path.join("diff")).unwrap();
+            fs::write(path.join("link"), format!("SHORT{}", index)).unwrap();
+            fs::write(path.join("lower"), lower.join(":")).unwrap();
+            lower.insert(0, format!("l/SHORT{}", index));
+        }
+        let mut graph = HashMap::new();
+        analyze_overlay2(base, &mut graph, &HashSet::new()).unwrap();
+        graph
+    }
+
+    #[test]
+    fn deep_overlay_chain_is_reported_with_its_shortest_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let graph = overlay_chain(dir.path(), 40);
+
+        let missing = graph.get("MissingNode:ShortLink:GONE").unwrap();
+        let paths = root_paths(missing);
+        // every layer names the missing link in its lower, the top one is the only root
+        assert_eq!(paths.len(), 1);
+        let path: Vec<String> = paths[0].iter().map(|node| node.borrow().id()).collect();
+        assert_eq!(path, vec!["MissingNode:ShortLink:GONE".to_string(), "Overlay2:layer39".to_string()]);
+        assert_eq!(run_doctor(&graph).exit_code(), Verdict::Error.exit_code());
+    }
+
+    #[test]
+    fn missing_node_stored_under_the_id_it_stands_for_is_an_error() {
+        let mut graph: HashMap<String, Rc<RefCell<dyn Node>>> = HashMap::new();
+        let missing: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
+            id: "Overlay2:gone".to_string(),
+            deps: Vec::new(),
+            rdeps: Vec::new(),
+        }));
+        graph.insert("Overlay2:gone".to_string(), missing);
+
+        assert_eq!(classify_layers(&graph).get("MissingNode").map(Vec::len), Some(1));
+        assert_eq!(run_doctor(&graph).exit_code(), Verdict::Error.exit_code());
+    }
+
+    #[test]
+    fn incomplete_overlay_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("overlay2/layer0");
+        fs::create_dir_all(&path).unwrap();
+        fs::create_dir_all(dir.path().join("overlay2/l")).unwrap();
+        fs::write(path.join("link"), "SHORT0").unwrap();
+        std::os::unix::fs::symlink("../layer0/diff", dir.path().join("overlay2/l/SHORT0")).unwrap();
+
+        // no diff, nothing else is wrong
+        let mut graph = HashMap::new();
+        analyze_overlay2(dir.path(), &mut graph, &HashSet::new()).unwrap();
+        assert!(!classify_layers(&graph).contains_key("MissingNode"));
+        assert_eq!(run_doctor(&graph).exit_code(), Verdict::Error.exit_code());
+    }
+}
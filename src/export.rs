@@ -0,0 +1,249 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::cell::RefCell;
+use anyhow::Result;
+use serde::Serialize;
+use crate::analysis::{find_node, is_dangling, unique_nodes};
+use crate::node::Node;
+
+/// Bumped whenever a field is renamed, removed or changes meaning.
+/// Adding a field keeps the same version.
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct JsonGraph {
+    schema_version: u32,
+    tool_version: &'static str,
+    base: PathBuf,
+    nodes: Vec<JsonNode>,
+}
+
+#[derive(Serialize)]
+struct JsonNode {
+    id: String,
+    #[serde(rename = "type")]
+    node_type: String,
+    paths: Vec<PathBuf>,
+    deps: Vec<String>,
+    rdeps: Vec<String>,
+    dangling: bool,
+    missing: bool,
+    in_use: bool,
+    /// Extra information, like the error of a Corrupt node
+    details: Vec<String>,
+}
+
+fn node_ids(nodes: &[Rc<RefCell<dyn Node>>]) -> Vec<String> {
+    let mut ids: Vec<String> = nodes.iter().map(|node| node.borrow().id()).collect();
+    ids.sort();
+    ids
+}
+
+/// Writes every node of the graph, sorted by id so two snapshots of the same host can be diffed.
+pub fn write_json(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, base_path: &Path, writer: &mut dyn Write) -> Result<()> {
+    // aliases, like LayerDiffId:<diff> for image layers, are exported once under their own id
+    let mut nodes: Vec<JsonNode> = unique_nodes(graph).iter()
+        .map(|node| {
+            let node = node.borrow();
+            let id = node.id();
+            let node_type = id.split(':').next().unwrap_or("Unknown").to_string();
+            let missing = node_type == "MissingNode";
+            JsonNode {
+                paths: node.paths(),
+                deps: node_ids(node.deps()),
+                rdeps: node_ids(node.rdeps()),
+                dangling: is_dangling(&*node),
+                missing,
+                in_use: node.in_use(),
+                details: node.details(),
+                id,
+                node_type,
+            }
+        })
+        .collect();
+    nodes.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let json_graph = JsonGraph {
+        schema_version: JSON_SCHEMA_VERSION,
+        tool_version: env!("CARGO_PKG_VERSION"),
+        base: base_path.to_path_buf(),
+        nodes,
+    };
+    serde_json::to_writer_pretty(&mut *writer, &json_graph)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+fn dot_escape(id: &str) -> String {
+    id.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Writes the graph in Graphviz DOT format, edges go from a node to its deps.
+/// With `from`, only the nodes reachable from that node through deps are written.
+pub fn write_dot(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, from: Option<&str>, writer: &mut dyn Write) -> Result<()> {
+    let mut nodes: Vec<Rc<RefCell<dyn Node>>> = match from {
+        Some(from) => {
+            let root = match find_node(graph, from) {
+                Some(root) => root,
+                None => anyhow::bail!("Node {} not found", from),
+            };
+            let mut visited = HashSet::new();
+            let mut stack = vec![root];
+            let mut nodes = Vec::new();
+            while let Some(node) = stack.pop() {
+                if !visited.insert(node.borrow().id()) {
+                    continue;
+                }
+                for dep in node.borrow().deps() {
+                    stack.push(Rc::clone(dep));
+                }
+                nodes.push(node);
+            }
+            nodes
+        }
+        None => unique_nodes(graph),
+    };
+    nodes.sort_by_key(|node| node.borrow().id());
+
+    writeln!(writer, "digraph docker {{")?;
+    writeln!(writer, "    rankdir=LR;")?;
+    writeln!(writer, "    node [shape=box];")?;
+    for node in &nodes {
+        let node = node.borrow();
+        let id = node.id();
+        let attributes = if id.starts_with("MissingNode:") {
+            " [color=red, fontcolor=red]"
+        } else if is_dangling(&*node) {
+            " [style=filled, fillcolor=orange]"
+        } else {
+            ""
+        };
+        writeln!(writer, "    \"{}\"{};", dot_escape(&id), attributes)?;
+    }
+    for node in &nodes {
+        let node = node.borrow();
+        let mut dep_ids = node_ids(node.deps());
+        dep_ids.dedup();
+        for dep_id in dep_ids {
+            writeln!(writer, "    \"{}\" -> \"{}\";", dot_escape(&node.id()), dot_escape(&dep_id))?;
+        }
+    }
+    writeln!(writer, "}}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{analyze, image_store};
+    use crate::node::MissingNode;
+
+    fn json_nodes(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> Vec<serde_json::Value> {
+        let mut output = Vec::new();
+        write_json(graph, Path::new("/var/lib/docker"), &mut output).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        json["nodes"].as_array().unwrap().clone()
+    }
+
+    #[test]
+    fn json_has_missing_nodes_stored_under_another_key() {
+        let dir = image_store();
+        let mut graph = analyze(dir.path());
+        // like the lower of an overlay, keyed by the id of the node it stands for
+        let missing: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
+            id: "Overlay2:gone".to_string(),
+            deps: Vec::new(),
+            rdeps: Vec::new(),
+        }));
+        graph.insert("Overlay2:gone".to_string(), missing);
+
+        let nodes = json_nodes(&graph);
+        let missing = nodes.iter().find(|node| node["id"] == "MissingNode:Overlay2:gone").unwrap();
+        assert_eq!(missing["missing"], true);
+        assert_eq!(missing["dangling"], false);
+        // aliases are exported once, under their own id
+        let ids: HashSet<&str> = nodes.iter().map(|node| node["id"].as_str().unwrap()).collect();
+        assert_eq!(ids.len(), nodes.len());
+    }
+
+    #[test]
+    fn json_dangling_agrees_with_the_doctor() {
+        let dir = image_store();
+        let graph = analyze(dir.path());
+        let nodes = json_nodes(&graph);
+        // the tag is what the user keeps, so nothing is dangling
+        assert!(nodes.iter().all(|node| node["dangling"] == false));
+
+        std::fs::remove_file(dir.path().join("image/overlay2/repositories.json")).unwrap();
+        let graph = analyze(dir.path());
+        let dangling: Vec<String> = json_nodes(&graph).iter()
+            .filter(|node| node["dangling"] == true)
+            .map(|node| node["id"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(dangling, vec![format!("ImageContent:{}", crate::fixtures::IMAGE_ID)]);
+    }
+
+    #[test]
+    fn dot_declares_every_node_it_draws_an_edge_to() {
+        let dir = image_store();
+        std::fs::remove_dir_all(dir.path().join("overlay2/cache1")).unwrap();
+        let mut graph = analyze(dir.path());
+        let missing: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
+            id: "Overlay2:gone".to_string(),
+            deps: Vec::new(),
+            rdeps: Vec::new(),
+        }));
+        graph.insert("Overlay2:gone".to_string(), missing);
+
+        let mut output = Vec::new();
+        write_dot(&graph, None, &mut output).unwrap();
+        let dot = String::from_utf8(output).unwrap();
+        let declared: HashSet<&str> = dot.lines()
+            .filter(|line| !line.contains("->") && line.trim_start().starts_with('"'))
+            .map(|line| line.trim().split('"').nth(1).unwrap())
+            .collect();
+        assert!(declared.contains("MissingNode:Overlay2:gone"));
+        for line in dot.lines().filter(|line| line.contains("->")) {
+            assert!(declared.contains(line.split('"').nth(3).unwrap()), "{}", line);
+        }
+    }
+
+    #[test]
+    fn dot_from_finds_aliased_and_missing_nodes() {
+        let dir = image_store();
+        std::fs::remove_dir_all(dir.path().join("overlay2/cache1")).unwrap();
+        let graph = analyze(dir.path());
+        let layer = graph.values()
+            .find(|node| node.borrow().id().starts_with("ImageLayer:"))
+            .unwrap()
+            .borrow()
+            .id();
+        let missing = graph.values()
+            .find(|node| node.borrow().id().starts_with("MissingNode:"))
+            .unwrap()
+            .borrow()
+            .id();
+        for from in [layer, missing] {
+            let mut output = Vec::new();
+            write_dot(&graph, Some(&from), &mut output).unwrap();
+            assert!(String::from_utf8(output).unwrap().contains(&format!("\"{}\"", from)));
+        }
+        assert!(write_dot(&graph, Some("Overlay2:nope"), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn dot_fills_only_dangling_roots() {
+        let dir = image_store();
+        std::fs::remove_file(dir.path().join("image/overlay2/repositories.json")).unwrap();
+        let graph = analyze(dir.path());
+        let mut output = Vec::new();
+        write_dot(&graph, None, &mut output).unwrap();
+        let filled: Vec<String> = String::from_utf8(output).unwrap().lines()
+            .filter(|line| line.contains("orange"))
+            .map(|line| line.trim().split('"').nth(1).unwrap().to_string())
+            .collect();
+        assert_eq!(filled, vec![format!("ImageContent:{}", crate::fixtures::IMAGE_ID)]);
+    }
+}
@@ -0,0 +1,105 @@
+// Docker roots and helpers shared by the tests of every module
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::rc::Rc;
+use crate::analysis::DeleteOptions;
+use crate::image::{analyze_images, chain_ids, IMAGEDB_PATH, LAYERDB_PATH, METADATA_DIFFID_PATH, REPOSITORIES_PATH};
+use crate::journal::Journal;
+use crate::node::Node;
+use crate::overlay2::{analyze_overlay2, short_link_target};
+use crate::trash::Remover;
+
+pub const IMAGE_ID: &str = "1c1f4bd3a61de5e0c5a0e1f8ef1b16bc63e7d26b4a8a7f0a6d8f2b1e4c3d2a10";
+pub const SECOND_IMAGE_ID: &str = "2c2f4bd3a61de5e0c5a0e1f8ef1b16bc63e7d26b4a8a7f0a6d8f2b1e4c3d2a20";
+pub const DIFF_IDS: [&str; 3] = [
+    "sha256:1111111111111111111111111111111111111111111111111111111111111111",
+    "sha256:2222222222222222222222222222222222222222222222222222222222222222",
+    "sha256:3333333333333333333333333333333333333333333333333333333333333333",
+];
+
+pub fn write(path: &Path, content: impl AsRef<[u8]>) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}
+
+pub fn dep_ids(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, id: &str) -> Vec<String> {
+    graph.get(id).unwrap().borrow().deps().iter().map(|dep| dep.borrow().id()).collect()
+}
+
+/// An overlay2 dir with the layer `id` and its short link `short_link`, built on the `lower` short links
+pub fn overlay(base: &Path, id: &str, short_link: &str, lower: &[&str]) {
+    let path = base.join("overlay2").join(id);
+    write(&path.join("link"), short_link);
+    fs::create_dir_all(path.join("diff")).unwrap();
+    if !lower.is_empty() {
+        let lower: Vec<String> = lower.iter().map(|lower| format!("l/{}", lower)).collect();
+        write(&path.join("lower"), lower.join(":"));
+    }
+    fs::create_dir_all(base.join("overlay2/l")).unwrap();
+    symlink(short_link_target(id), base.join("overlay2/l").join(short_link)).unwrap();
+}
+
+/// A docker root with one image tagged app:latest, made of the layers of `DIFF_IDS`,
+/// each with its committed overlay2 dir `cache<index>` and short link `SHORT<index>`
+pub fn image_store() -> tempfile::TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    let base = dir.path();
+    fs::create_dir_all(base.join(METADATA_DIFFID_PATH)).unwrap();
+
+    let chain_ids = chain_ids(&DIFF_IDS);
+    for (index, chain_id) in chain_ids.iter().enumerate() {
+        let layer_path = base.join(LAYERDB_PATH).join(chain_id);
+        let cache_id = format!("cache{}", index);
+        write(&layer_path.join("cache-id"), &cache_id);
+        write(&layer_path.join("diff"), DIFF_IDS[index]);
+        if index > 0 {
+            write(&layer_path.join("parent"), format!("sha256:{}", chain_ids[index - 1]));
+        }
+        overlay(base, &cache_id, &format!("SHORT{}", index), &[]);
+        write(&base.join("overlay2").join(&cache_id).join("committed"), "");
+    }
+
+    let rootfs = serde_json::json!({
+        "created": "2024-01-01T00:00:00Z",
+        "os": "linux",
+        "architecture": "amd64",
+        "config": { "Labels": { "maintainer": "me" } },
+        "rootfs": { "type": "layers", "diff_ids": DIFF_IDS },
+    });
+    write(&base.join(IMAGEDB_PATH).join(IMAGE_ID), rootfs.to_string());
+    let repositories = serde_json::json!({ "Repositories": { "app": { "app:latest": format!("sha256:{}", IMAGE_ID) } } });
+    write(&base.join(REPOSITORIES_PATH), repositories.to_string());
+
+    dir
+}
+
+/// `image_store` without its tag, plus an untagged image `SECOND_IMAGE_ID` made of its two bottom layers
+pub fn untagged_images() -> tempfile::TempDir {
+    let dir = image_store();
+    fs::remove_file(dir.path().join(REPOSITORIES_PATH)).unwrap();
+    let rootfs = serde_json::json!({ "rootfs": { "type": "layers", "diff_ids": DIFF_IDS[..2] } });
+    write(&dir.path().join(IMAGEDB_PATH).join(SECOND_IMAGE_ID), rootfs.to_string());
+    dir
+}
+
+/// The overlay2 dirs and the images of `base`, without containers nor mounts
+pub fn analyze(base: &Path) -> HashMap<String, Rc<RefCell<dyn Node>>> {
+    let mut graph = HashMap::new();
+    analyze_overlay2(base, &mut graph, &HashSet::new()).unwrap();
+    analyze_images(base, &mut graph).unwrap();
+    graph
+}
+
+/// Deletes even with a live daemon on the test host, journaled in `<base>/journal`
+pub fn delete_options(base: &Path, remover: Remover) -> DeleteOptions {
+    DeleteOptions {
+        base_path: base.to_path_buf(),
+        containerd_path: None,
+        force: true,
+        remover,
+        journal: Journal::new(&base.join("journal")),
+    }
+}
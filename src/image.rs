@@ -1,15 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::rc::Rc;
 use std::cell::RefCell;
 use anyhow::{Result, Context};
 use serde_json::Value;
-use crate::node::{MissingNode, Node, StaticId};
-
-const LAYERDB_PATH: &str ="image/overlay2/layerdb/sha256";
-const IMAGEDB_PATH: &str ="image/overlay2/imagedb/content/sha256";
-const METADATA_DIFFID_PATH: &str = "image/overlay2/distribution/v2metadata-by-diffid/sha256";
+use sha2::{Digest, Sha256};
+use crate::diagnostics::{read_dir_entries, record_corrupt};
+use crate::node::{link_or_missing, Node, StaticId};
+use crate::trash::Remover;
+
+pub(crate) const LAYERDB_PATH: &str ="image/overlay2/layerdb/sha256";
+pub(crate) const IMAGEDB_PATH: &str ="image/overlay2/imagedb/content/sha256";
+pub(crate) const METADATA_DIFFID_PATH: &str = "image/overlay2/distribution/v2metadata-by-diffid/sha256";
+pub(crate) const REPOSITORIES_PATH: &str = "image/overlay2/repositories.json";
 const DIGESTID_PATH: &str = "image/overlay2/distribution/diffid-by-digest/sha256";
 
 pub struct ImageLayerNode {
@@ -47,12 +51,12 @@ impl Node for ImageLayerNode {
         &mut self.rdeps
     }
 
-    fn delete(&self) -> Result<()> {
-        match fs::remove_dir_all(self.base_path.join(LAYERDB_PATH).join(&self.layer_id)).context("Failed to remove image layer directory") {
-            Ok(_) => {},
-            Err(e) => eprintln!("Failed to dir file: {}", e),
-        };
-        Ok(())
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.base_path.join(LAYERDB_PATH).join(&self.layer_id)]
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        remover.remove_dir_all(&self.base_path.join(LAYERDB_PATH).join(&self.layer_id)).context("Failed to remove image layer directory")
     }
 }
 
@@ -62,6 +66,39 @@ pub struct ImageContentNode {
     deps: Vec<Rc<RefCell<dyn Node>>>,
     rdeps: Vec<Rc<RefCell<dyn Node>>>,
     base_path: PathBuf,
+    info: ImageInfo,
+}
+
+/// What the imagedb JSON tells about an image, empty when it could not be read
+#[derive(Default)]
+struct ImageInfo {
+    created: Option<String>,
+    architecture: Option<String>,
+    os: Option<String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl ImageInfo {
+    fn from_config(config: &Value) -> ImageInfo {
+        let labels = config["config"]["Labels"].as_object()
+            .map(|labels| labels.iter()
+                .map(|(key, value)| (key.clone(), value.as_str().unwrap_or_default().to_string()))
+                .collect())
+            .unwrap_or_default();
+        ImageInfo {
+            created: config["created"].as_str().map(str::to_string),
+            architecture: config["architecture"].as_str().map(str::to_string),
+            os: config["os"].as_str().map(str::to_string),
+            labels,
+        }
+    }
+
+    fn platform(&self) -> Option<String> {
+        match (&self.os, &self.architecture) {
+            (Some(os), Some(architecture)) => Some(format!("{}/{}", os, architecture)),
+            (os, architecture) => os.clone().or(architecture.clone()),
+        }
+    }
 }
 
 impl StaticId for ImageContentNode {
@@ -91,10 +128,43 @@ impl Node for ImageContentNode {
         &mut self.rdeps
     }
 
-    fn delete(&self) -> Result<()> {
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.base_path.join(IMAGEDB_PATH).join(&self.image_id)]
+    }
+
+    // the tags are the ImageRepo rdeps
+    fn label(&self) -> String {
+        let mut label = self.id();
+        let tags: Vec<String> = self.rdeps.iter()
+            .filter_map(|rdep| rdep.borrow().id().strip_prefix("ImageRepo:").map(str::to_string))
+            .collect();
+        if !tags.is_empty() {
+            label.push_str(&format!(" {}", tags.join(", ")));
+        }
+        if let Some(created) = &self.info.created {
+            label.push_str(&format!(" ({})", created));
+        }
+        label
+    }
+
+    fn details(&self) -> Vec<String> {
+        let info = &self.info;
+        let mut details: Vec<String> = [("Created", info.created.clone()), ("Platform", info.platform())].iter()
+            .filter_map(|(title, value)| value.as_ref().map(|value| format!("{}: {}", title, value)))
+            .collect();
+        if !info.labels.is_empty() {
+            details.push("Labels:".to_string());
+            for (key, value) in &info.labels {
+                details.push(format!("    {}={}", key, value));
+            }
+        }
+        details
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
         let path = self.base_path.join(IMAGEDB_PATH).join(&self.image_id);
 
-        match fs::remove_file(&path).context(format!("Failed to remove image content file {}", &path.display())) {
+        match remover.remove_file(&path).context(format!("Failed to remove image content file {}", &path.display())) {
             Ok(_) => {},
             Err(e) => {
                 eprintln!("Failed to remove file: {}", e);
@@ -135,16 +205,20 @@ impl Node for MetadataDiffIdNode {
     fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
         &mut self.rdeps
     }
-    fn delete(&self) -> Result<()> {
-        match fs::remove_file(self.base_path.join(METADATA_DIFFID_PATH).join(&self.id)) {
-            Ok(()) => {},
-            Err(e) => eprintln!("Failed to remove file: {}", e),
-        }
-        match fs::remove_file(self.base_path.join(DIGESTID_PATH).join(&self.digest)) {
-            Ok(()) => {},
-            Err(e) => eprintln!("Failed to remove file: {}", e),
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![
+            self.base_path.join(METADATA_DIFFID_PATH).join(&self.id),
+            self.base_path.join(DIGESTID_PATH).join(&self.digest),
+        ]
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        remover.remove_file(&self.base_path.join(METADATA_DIFFID_PATH).join(&self.id)).context("Failed to remove v2metadata file")?;
+        // the digest file is not always written, like for a layer built locally
+        match remover.remove_file(&self.base_path.join(DIGESTID_PATH).join(&self.digest)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context("Failed to remove diffid-by-digest file"),
+            _ => Ok(()),
         }
-        Ok(())
     }
 }
 
@@ -188,16 +262,24 @@ impl Node for LayerDiffIdNode {
     fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
         &mut self.rdeps
     }
-    fn delete(&self) -> Result<()> {
+
+    fn paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    fn delete(&self, _remover: &Remover) -> Result<()> {
         Ok(())
     }
 }
 
 
 pub struct ImageRepoNode {
-    name_tag: String,
+    repository: String,
+    /// Full reference, like `nginx:latest`, docker keys the tags of a repository with it
+    tag: String,
     deps: Vec<Rc<RefCell<dyn Node>>>,
     rdeps: Vec<Rc<RefCell<dyn Node>>>,
+    base_path: PathBuf,
 }
 
 impl StaticId for ImageRepoNode {
@@ -208,7 +290,7 @@ impl StaticId for ImageRepoNode {
 
 impl Node for ImageRepoNode {
     fn id(&self) -> String {
-        Self::static_id(&self.name_tag)
+        Self::static_id(&self.tag)
     }
 
     fn deps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
@@ -227,8 +309,29 @@ impl Node for ImageRepoNode {
         &mut self.rdeps
     }
 
-    fn delete(&self) -> Result<()> {
-        Ok(()) // Repositories are not deleted directly
+    fn paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    // a tag is an entry of repositories.json, which is edited in place, quarantine keeps a copy of the file first
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        let repo_file = self.base_path.join(REPOSITORIES_PATH);
+        remover.keep_before_edit(&repo_file).context(format!("Failed to keep a copy of {}", repo_file.display()))?;
+        let mut json = read_json(&repo_file).context(format!("Failed to read {}", repo_file.display()))?;
+        if let Some(repos) = json.get_mut("Repositories").and_then(Value::as_object_mut) {
+            if let Some(tags) = repos.get_mut(&self.repository).and_then(Value::as_object_mut) {
+                tags.remove(&self.tag);
+                if tags.is_empty() {
+                    repos.remove(&self.repository);
+                }
+            }
+        }
+
+        // written aside then renamed, so docker never reads a truncated file
+        let tmp_file = repo_file.with_extension("json.tmp");
+        fs::write(&tmp_file, serde_json::to_string(&json)?).context(format!("Failed to write {}", tmp_file.display()))?;
+        fs::rename(&tmp_file, &repo_file).context(format!("Failed to replace {}", repo_file.display()))?;
+        Ok(())
     }
 }
 
@@ -237,14 +340,24 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
     // Analyse diff ID
     let diffid_fullpath = base_path.join(METADATA_DIFFID_PATH);
  // also would need to check pending file in DIGESTID_PATH
-    for entry in fs::read_dir(&diffid_fullpath)? {
-        let entry = entry?;
+    for entry in read_dir_entries(graph, &diffid_fullpath) {
         let diff_id = entry.file_name().to_string_lossy().into_owned();
         let file_path = entry.path();
-        let content = fs::read_to_string(&file_path)?;
-        let json: Value = serde_json::from_str(&content)?;
+        let json = match read_json(&file_path) {
+            Ok(json) => json,
+            Err(e) => {
+                record_corrupt(graph, &file_path, &e, None);
+                continue;
+            }
+        };
 
-        let diff_id_array = json.as_array().unwrap();
+        let diff_id_array = match json.as_array() {
+            Some(diff_id_array) if !diff_id_array.is_empty() => diff_id_array,
+            _ => {
+                record_corrupt(graph, &file_path, &"expected a non empty array", None);
+                continue;
+            }
+        };
         /* there is often more than one entry in that file.
             it seems it's always the same digest in all those entreis
             there is a HMAC which is different though
@@ -252,7 +365,7 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
         /* if diff_id_array.len() > 1 {
             println!("more that one entry in {}", entry.path().to_str().unwrap());
         } */
-        if let Some(sha_digest) = diff_id_array.get(0).unwrap().get("Digest") {
+        if let Some(sha_digest) = diff_id_array[0].get("Digest") {
             let digest = sha_digest.as_str().unwrap_or_default().trim_start_matches("sha256:");
             let diff_id_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MetadataDiffIdNode {
                 id: diff_id.clone(),
@@ -271,8 +384,8 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
 
     // Analyze layer diff IDs
     let layerdb_path = base_path.join(LAYERDB_PATH);
-    for entry in fs::read_dir(&layerdb_path)? {
-        let entry = entry?;
+    let layer_entries = read_dir_entries(graph, &layerdb_path);
+    for entry in &layer_entries {
         let layer_id = entry.file_name().to_string_lossy().into_owned();
 
         let image_layer_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(ImageLayerNode {
@@ -284,29 +397,26 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
         }));
 
         let cache_id_path = entry.path().join("cache-id");
-        let cache_overlay_id = fs::read_to_string(cache_id_path)?.trim().to_string();
-        let overlay2_id = format!("Overlay2:{}", cache_overlay_id);
-        match graph.get(&overlay2_id) {
-            Some(node) => {
-                image_layer_node.borrow_mut().deps_mut().push(Rc::clone(node));
-                node.borrow_mut().rdeps_mut().push(Rc::clone(&image_layer_node));
-            }
-            None => {
-                let mut rdeps = Vec::new();
-                rdeps.push(Rc::clone(&image_layer_node));
-                let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
-                    id: overlay2_id,
-                    deps: Vec::new(),
-                    rdeps,
-                }));
-                image_layer_node.borrow_mut().deps_mut().push(Rc::clone(&missing_node));
-                let id_missing = missing_node.borrow().id();
-                graph.insert(id_missing, missing_node);
+        let overlay2_id = match fs::read_to_string(&cache_id_path) {
+            Ok(cache_overlay_id) => Some(format!("Overlay2:{}", cache_overlay_id.trim())),
+            Err(e) => {
+                record_corrupt(graph, &cache_id_path, &e, Some(&image_layer_node));
+                None
             }
+        };
+        if let Some(overlay2_id) = overlay2_id {
+            link_or_missing(graph, &image_layer_node, &overlay2_id);
         }
         
         let diff_path = entry.path().join("diff");
-        let diff_content = fs::read_to_string(diff_path)?;
+        let diff_content = match fs::read_to_string(&diff_path) {
+            Ok(diff_content) => diff_content,
+            Err(e) => {
+                record_corrupt(graph, &diff_path, &e, Some(&image_layer_node));
+                graph.insert(ImageLayerNode::static_id(&layer_id), image_layer_node);
+                continue;
+            }
+        };
         let diff_id_id = diff_content.trim_start_matches("sha256:");
         /*
         let diff_id_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(LayerDiffIdNode {
@@ -328,7 +438,7 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
         let metadata_diff_id_node_id = format!("MetadataDiffId:{}", diff_id_id);
         match graph.get(&metadata_diff_id_node_id) {
             Some(metadata_diff_id_node) => {
-                image_layer_node.borrow_mut().deps_mut().push(Rc::clone(&metadata_diff_id_node));
+                image_layer_node.borrow_mut().deps_mut().push(Rc::clone(metadata_diff_id_node));
                 metadata_diff_id_node.borrow_mut().rdeps_mut().push(Rc::clone(&image_layer_node));
 //                println!("found metadata_diff_id for ImageLayerNode {} {} in {}", &layer_id, &metadata_diff_id_node_id, METADATA_DIFFID_PATH);
             }
@@ -338,31 +448,43 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
         }
         graph.insert(ImageLayerNode::static_id(&layer_id), image_layer_node);
     }
-    for entry in fs::read_dir(&layerdb_path)? {
-        let entry = entry?;
+    for entry in &layer_entries {
         let layer_id = entry.file_name().to_string_lossy().into_owned();
 
-        let image_layer_node = graph.get(&ImageLayerNode::static_id(&layer_id)).unwrap();
+        let image_layer_node = match graph.get(&ImageLayerNode::static_id(&layer_id)) {
+            Some(image_layer_node) => Rc::clone(image_layer_node),
+            None => continue,
+        };
 
         let layer_parent_id_path = entry.path().join("parent");
         if let Ok(layer_parent_id) = fs::read_to_string(layer_parent_id_path) {
             let layer_parent_id = layer_parent_id.trim().to_string();
             let layer_parent_id = layer_parent_id.trim_start_matches("sha256:");
 
-            let layer_parent_node = graph.get(&ImageLayerNode::static_id(&layer_parent_id)).unwrap();
-
-            layer_parent_node.borrow_mut().rdeps_mut().push(Rc::clone(&image_layer_node));
-            image_layer_node.borrow_mut().deps_mut().push(Rc::clone(&layer_parent_node));
+            link_or_missing(graph, &image_layer_node, &ImageLayerNode::static_id(layer_parent_id));
         }
     }
 
     // Analyze image content
     let imagedb_path = base_path.join(IMAGEDB_PATH);
-    for entry in fs::read_dir(&imagedb_path)? {
-        let entry = entry?;
+    for entry in read_dir_entries(graph, &imagedb_path) {
         let image_id = entry.file_name().to_string_lossy().into_owned();
-        let content = fs::read_to_string(entry.path())?;
-        let json: Value = serde_json::from_str(&content)?;
+        let json = match read_json(&entry.path()) {
+            Ok(json) => json,
+            Err(e) => {
+                // the image is still listed, with the broken config as its dep
+                let node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(ImageContentNode {
+                    image_id: image_id.clone(),
+                    deps: Vec::new(),
+                    rdeps: Vec::new(),
+                    base_path: base_path.to_path_buf(),
+                    info: ImageInfo::default(),
+                }));
+                record_corrupt(graph, &entry.path(), &e, Some(&node));
+                graph.insert(format!("ImageContent:{}", image_id), node);
+                continue;
+            }
+        };
 
         if let Some(rootfs) = json.get("rootfs") {
             if let Some(diff_ids) = rootfs.get("diff_ids") {
@@ -372,24 +494,15 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
                         deps: Vec::new(),
                         rdeps: Vec::new(),
                         base_path: base_path.to_path_buf(),
+                        info: ImageInfo::from_config(&json),
                     }));
-                    for diff_id in diff_ids {
-                        let layer_diff_id = diff_id.as_str().unwrap_or("").trim_start_matches("sha256:");
-                        let layer_node_id = format!("DiffId:{}", layer_diff_id);
-                        if let Some(layer_node) = graph.get(&layer_node_id) {
-                            node.borrow_mut().deps.push(Rc::clone(layer_node));
-                            layer_node.borrow_mut().rdeps_mut().push(Rc::clone(&node) as Rc<RefCell<dyn Node>>);
-                        } else {
-                            let mut rdeps: Vec<Rc<RefCell<dyn Node>>> = Vec::new();
-                            rdeps.push(Rc::clone(&node) as Rc<RefCell<dyn Node>>);
-                            let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
-                                id: layer_node_id.clone(),
-                                deps: Vec::new(),
-                                rdeps,
-                            }));
-                            node.borrow_mut().deps.push(Rc::clone(&missing_node));
-                            graph.insert(layer_node_id, missing_node);
-                        }
+                    let diff_ids: Vec<&str> = diff_ids.iter()
+                        .map(|diff_id| diff_id.as_str().unwrap_or(""))
+                        .collect();
+                    // the image only depends on its top layer, the lower ones come through the parent links
+                    if let Some(top_chain_id) = chain_ids(&diff_ids).pop() {
+                        let owner: Rc<RefCell<dyn Node>> = node.clone();
+                        link_or_missing(graph, &owner, &ImageLayerNode::static_id(&top_chain_id));
                     }
                     graph.insert(format!("ImageContent:{}", image_id), node);
                 }
@@ -398,37 +511,69 @@ pub fn analyze_images(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<d
     }
 
     // Analyze repositories
-    let repositories = read_repositories(base_path)?;
-    for (name_tag, image_id) in repositories {
+    let repo_file = base_path.join(REPOSITORIES_PATH);
+    let repositories = match read_repositories(&repo_file) {
+        Ok(repositories) => repositories,
+        Err(e) => {
+            if repo_file.exists() {
+                record_corrupt(graph, &repo_file, &e, None);
+            }
+            Vec::new()
+        }
+    };
+    for (repository, tag, image_id) in repositories {
         let content_node_id = format!("ImageContent:{}", image_id);
         if let Some(content_node) = graph.get(&content_node_id) {
+            let node_id = ImageRepoNode::static_id(&tag);
             let node = Rc::new(RefCell::new(ImageRepoNode {
-                name_tag: name_tag.clone(),
+                repository,
+                tag,
                 deps: vec![Rc::clone(content_node)],
                 rdeps: Vec::new(),
+                base_path: base_path.to_path_buf(),
             }));
             content_node.borrow_mut().rdeps_mut().push(Rc::clone(&node) as Rc<RefCell<dyn Node>>);
-            graph.insert(format!("ImageRepo:{}", name_tag), node);
+            graph.insert(node_id, node);
         }
     }
 
     Ok(())
 }
 
-fn read_repositories(base_path: &Path) -> Result<HashMap<String, String>> {
-    let repo_file = base_path.join("image/overlay2/repositories.json");
-    let content = fs::read_to_string(repo_file)?;
-    let json: Value = serde_json::from_str(&content)?;
+/// Chain IDs of the layers of an image, from `rootfs.diff_ids`, bottom layer first.
+/// layerdb is keyed by chain ID: the bottom one is its diff ID, the next ones are
+/// sha256("<parent chain ID> <diff ID>"), both with their sha256: prefix.
+pub fn chain_ids(diff_ids: &[&str]) -> Vec<String> {
+    let mut chain_ids: Vec<String> = Vec::new();
+    for diff_id in diff_ids {
+        let diff_id = diff_id.trim_start_matches("sha256:");
+        let chain_id = match chain_ids.last() {
+            Some(parent) => format!("{:x}", Sha256::digest(format!("sha256:{} sha256:{}", parent, diff_id))),
+            None => diff_id.to_string(),
+        };
+        chain_ids.push(chain_id);
+    }
+    chain_ids
+}
 
-    let mut repositories = HashMap::new();
+fn read_json(path: &Path) -> Result<Value> {
+    let content = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Repository, reference and image id of each entry of repositories.json,
+/// the references already hold the repository, like `nginx:latest` under `nginx`
+fn read_repositories(repo_file: &Path) -> Result<Vec<(String, String, String)>> {
+    let json = read_json(repo_file)?;
+
+    let mut repositories = Vec::new();
     if let Some(repos) = json.get("Repositories") {
         if let Some(repos) = repos.as_object() {
             for (repo, tags) in repos {
                 if let Some(tags) = tags.as_object() {
                     for (tag, digest) in tags {
-                        let name_tag = format!("{}:{}", repo, tag);
                         let image_id = digest.as_str().unwrap_or("").trim_start_matches("sha256:");
-                        repositories.insert(name_tag, image_id.to_string());
+                        repositories.push((repo.clone(), tag.clone(), image_id.to_string()));
                     }
                 }
             }
@@ -436,4 +581,96 @@ fn read_repositories(base_path: &Path) -> Result<HashMap<String, String>> {
     }
 
     Ok(repositories)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::DeletionPlan;
+    use crate::fixtures::{analyze, dep_ids, image_store, write, DIFF_IDS, IMAGE_ID};
+
+    #[test]
+    fn chain_ids_hash_parent_and_diff_id() {
+        assert_eq!(chain_ids(&["sha256:aaa", "sha256:bbb"]), vec![
+            "aaa".to_string(),
+            "56efb1d4f6c79b745d37d6eff87e3ed8dd2be28104e124ba73fd6e6c4892c792".to_string(),
+        ]);
+        assert!(chain_ids(&[]).is_empty());
+    }
+
+    #[test]
+    fn image_content_depends_on_its_top_layer() {
+        let dir = image_store();
+        let graph = analyze(dir.path());
+        let chain_ids = chain_ids(&DIFF_IDS);
+
+        let image_id = ImageContentNode::static_id(IMAGE_ID);
+        assert_eq!(dep_ids(&graph, &image_id), vec![ImageLayerNode::static_id(&chain_ids[2])]);
+        assert!(dep_ids(&graph, &ImageLayerNode::static_id(&chain_ids[2])).contains(&ImageLayerNode::static_id(&chain_ids[1])));
+        assert!(dep_ids(&graph, &ImageLayerNode::static_id(&chain_ids[1])).contains(&ImageLayerNode::static_id(&chain_ids[0])));
+        assert!(graph.keys().all(|key| !key.starts_with("MissingNode:")));
+
+        // the image is tagged, so neither it nor its layers are dangling
+        assert!(!graph.get(&image_id).unwrap().borrow().rdeps().is_empty());
+        for chain_id in &chain_ids {
+            assert!(!graph.get(&ImageLayerNode::static_id(chain_id)).unwrap().borrow().rdeps().is_empty());
+        }
+    }
+
+    #[test]
+    fn missing_top_layer_is_reported() {
+        let dir = image_store();
+        let top_chain_id = chain_ids(&DIFF_IDS).pop().unwrap();
+        fs::remove_dir_all(dir.path().join(LAYERDB_PATH).join(&top_chain_id)).unwrap();
+
+        let graph = analyze(dir.path());
+        let missing_id = format!("MissingNode:{}", ImageLayerNode::static_id(&top_chain_id));
+        assert_eq!(dep_ids(&graph, &ImageContentNode::static_id(IMAGE_ID)), vec![missing_id.clone()]);
+        assert_eq!(graph.get(&missing_id).unwrap().borrow().id(), missing_id);
+    }
+
+    #[test]
+    fn orphaned_parent_is_reported() {
+        let dir = image_store();
+        let chain_ids = chain_ids(&DIFF_IDS);
+        fs::remove_dir_all(dir.path().join(LAYERDB_PATH).join(&chain_ids[1])).unwrap();
+
+        let graph = analyze(dir.path());
+        let missing_id = format!("MissingNode:{}", ImageLayerNode::static_id(&chain_ids[1]));
+        assert_eq!(dep_ids(&graph, &ImageLayerNode::static_id(&chain_ids[2])), vec!["Overlay2:cache2".to_string(), missing_id.clone()]);
+        assert_eq!(graph.get(&missing_id).unwrap().borrow().rdeps().len(), 1);
+        // nothing references the bottom layer anymore
+        assert!(graph.get(&ImageLayerNode::static_id(&chain_ids[0])).unwrap().borrow().rdeps().is_empty());
+    }
+
+    #[test]
+    fn orphaned_parent_can_be_planned_for_deletion() {
+        let dir = image_store();
+        let chain_ids = chain_ids(&DIFF_IDS);
+        write(&dir.path().join(LAYERDB_PATH).join(&chain_ids[1]).join("parent"), "sha256:0000");
+
+        let graph = analyze(dir.path());
+        let missing_id = format!("MissingNode:{}", ImageLayerNode::static_id("0000"));
+        assert!(dep_ids(&graph, &ImageLayerNode::static_id(&chain_ids[1])).contains(&missing_id));
+
+        let plan = DeletionPlan::new(&graph, &ImageRepoNode::static_id("app:latest"), true).unwrap();
+        let planned: Vec<&str> = plan.steps.iter().map(|step| step.node_id.as_str()).collect();
+        assert!(planned.contains(&missing_id.as_str()));
+        assert!(planned.contains(&ImageLayerNode::static_id(&chain_ids[1]).as_str()));
+    }
+
+    #[test]
+    fn image_content_shows_its_metadata() {
+        let dir = image_store();
+        let graph = analyze(dir.path());
+        let image = graph.get(&ImageContentNode::static_id(IMAGE_ID)).unwrap().borrow();
+
+        assert_eq!(image.label(), format!("ImageContent:{} app:latest (2024-01-01T00:00:00Z)", IMAGE_ID));
+        assert_eq!(image.details(), vec![
+            "Created: 2024-01-01T00:00:00Z".to_string(),
+            "Platform: linux/amd64".to_string(),
+            "Labels:".to_string(),
+            "    maintainer=me".to_string(),
+        ]);
+    }
+}
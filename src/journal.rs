@@ -0,0 +1,218 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::size::format_size;
+
+/// Default journal file, relative to the docker base directory
+pub const DEFAULT_JOURNAL: &str = ".docker-cleaner-journal.jsonl";
+
+/// One line of the journal, written after each successful `Node::delete` or created short link
+#[derive(Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub timestamp: String,
+    /// Start time of the docker-cleaner process, shared by all the entries of a run
+    pub run: String,
+    pub node_id: String,
+    pub node_type: String,
+    pub paths: Vec<PathBuf>,
+    pub size: u64,
+    /// Node the recursive deletion was started from
+    pub recursive_root: Option<String>,
+    /// Where the paths were moved to, in quarantine mode
+    pub quarantine: Option<PathBuf>,
+    /// The paths were created, like a short link written again by repair, instead of deleted
+    #[serde(default)]
+    pub created: bool,
+    pub user: String,
+    pub sudo_user: Option<String>,
+    pub tool_version: String,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    run: String,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Journal {
+        Journal {
+            path: path.to_path_buf(),
+            run: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        }
+    }
+
+    pub fn entry(&self, node_id: &str, paths: &[PathBuf], size: u64, recursive_root: Option<&str>, quarantine: Option<&Path>) -> JournalEntry {
+        JournalEntry {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            run: self.run.clone(),
+            node_id: node_id.to_string(),
+            node_type: node_id.split(':').next().unwrap_or("Unknown").to_string(),
+            paths: paths.to_vec(),
+            size,
+            recursive_root: recursive_root.map(str::to_string),
+            quarantine: quarantine.map(Path::to_path_buf),
+            created: false,
+            user: std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()),
+            sudo_user: std::env::var("SUDO_USER").ok(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    pub fn created_entry(&self, node_id: &str, path: &Path) -> JournalEntry {
+        JournalEntry { created: true, ..self.entry(node_id, &[path.to_path_buf()], 0, None, None) }
+    }
+
+    fn open(&self) -> Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context(format!("Failed to open journal {}", self.path.display()))
+    }
+
+    /// Fails if entries cannot be appended, to be called before deleting what they record
+    pub fn check(&self) -> Result<()> {
+        self.open().map(|_| ())
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> Result<()> {
+        let mut file = self.open()?;
+        // a single write per line, so concurrent runs do not interleave inside an entry
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes()).context(format!("Failed to write journal {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>> {
+    let content = fs::read_to_string(path).context(format!("Failed to read journal {}", path.display()))?;
+    content.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| serde_json::from_str(line).context(format!("{}:{}: malformed journal entry", path.display(), index + 1)))
+        .collect()
+}
+
+pub fn print_entries(entries: &[JournalEntry]) {
+    for entry in entries {
+        if entry.created {
+            println!("{} {} {} (created)", entry.timestamp, entry.user, entry.node_id);
+        } else {
+            println!("{} {} {} ({})", entry.timestamp, entry.user, entry.node_id, format_size(entry.size));
+        }
+        for path in &entry.paths {
+            println!("    {}", path.display());
+        }
+    }
+}
+
+/// One block per run: who ran it, how many nodes of each type were removed, and how many bytes
+pub fn print_summary(entries: &[JournalEntry]) {
+    // a closed stdout only loses the summary
+    let _ = write_summary(entries, &mut std::io::stdout());
+}
+
+fn write_summary(entries: &[JournalEntry], writer: &mut dyn Write) -> std::io::Result<()> {
+    let mut runs: BTreeMap<&str, Vec<&JournalEntry>> = BTreeMap::new();
+    for entry in entries {
+        runs.entry(&entry.run).or_default().push(entry);
+    }
+
+    for (run, entries) in runs {
+        let created = entries.iter().filter(|entry| entry.created).count();
+        let first = entries[0];
+        let entries: Vec<&JournalEntry> = entries.into_iter().filter(|entry| !entry.created).collect();
+        let size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let mut types: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut roots: Vec<&str> = Vec::new();
+        for entry in &entries {
+            *types.entry(&entry.node_type).or_default() += 1;
+            if let Some(root) = &entry.recursive_root {
+                if !roots.contains(&root.as_str()) {
+                    roots.push(root);
+                }
+            }
+        }
+        let user = match &first.sudo_user {
+            Some(sudo_user) => format!("{} (sudo by {})", first.user, sudo_user),
+            None => first.user.clone(),
+        };
+        let quarantined = entries.iter().filter(|entry| entry.quarantine.is_some()).count();
+
+        writeln!(writer, "run {} by {}, docker-cleaner {}", run, user, first.tool_version)?;
+        writeln!(writer, "    {} node(s), {}", entries.len(), format_size(size))?;
+        for (node_type, count) in types {
+            writeln!(writer, "    {}: {}", node_type, count)?;
+        }
+        if created > 0 {
+            writeln!(writer, "    {} short link(s) created", created)?;
+        }
+        if quarantined > 0 {
+            writeln!(writer, "    {} node(s) quarantined", quarantined)?;
+        }
+        for root in roots {
+            writeln!(writer, "    recursive from {}", root)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appended_entries_are_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let journal = Journal::new(&path);
+        journal.check().unwrap();
+
+        let paths = vec![PathBuf::from("/var/lib/docker/overlay2/cache0")];
+        journal.append(&journal.entry("Overlay2:cache0", &paths, 4096, Some("ImageContent:app"), None)).unwrap();
+        journal.append(&journal.entry("ShortLink:SHORT0", &[], 0, None, Some(Path::new("/trash")))).unwrap();
+        journal.append(&journal.created_entry("ShortLink:SHORT1", Path::new("/var/lib/docker/overlay2/l/SHORT1"))).unwrap();
+
+        let entries = read_journal(&path).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.node_id.as_str()).collect::<Vec<&str>>(),
+            vec!["Overlay2:cache0", "ShortLink:SHORT0", "ShortLink:SHORT1"]);
+        assert!(entries.iter().all(|entry| entry.run == entries[0].run));
+        assert_eq!(entries[0].node_type, "Overlay2");
+        assert_eq!(entries[0].paths, paths);
+        assert_eq!(entries[0].size, 4096);
+        assert_eq!(entries[0].recursive_root.as_deref(), Some("ImageContent:app"));
+        assert_eq!(entries[1].quarantine.as_deref(), Some(Path::new("/trash")));
+        assert_eq!(entries.iter().map(|entry| entry.created).collect::<Vec<bool>>(), vec![false, false, true]);
+
+        let mut summary = Vec::new();
+        write_summary(&entries, &mut summary).unwrap();
+        let summary = String::from_utf8(summary).unwrap();
+        assert_eq!(summary.lines().skip(1).collect::<Vec<&str>>(), vec![
+            "    2 node(s), 4.0 KiB",
+            "    Overlay2: 1",
+            "    ShortLink: 1",
+            "    1 short link(s) created",
+            "    1 node(s) quarantined",
+            "    recursive from ImageContent:app",
+        ]);
+    }
+
+    #[test]
+    fn entries_without_created_are_read_as_deletions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let journal = Journal::new(&path);
+        let mut entry = serde_json::to_value(journal.entry("Overlay2:cache0", &[], 0, None, None)).unwrap();
+        entry.as_object_mut().unwrap().remove("created");
+        fs::write(&path, format!("{}\n\n", entry)).unwrap();
+        assert!(!read_journal(&path).unwrap()[0].created);
+
+        fs::write(&path, format!("{}\n{{\"node_id\":\n", entry)).unwrap();
+        let error = read_journal(&path).err().unwrap();
+        assert!(format!("{}", error).ends_with(":2: malformed journal entry"));
+    }
+}
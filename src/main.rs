@@ -1,10 +1,24 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use std::path::PathBuf;
 
 mod analysis;
+mod bolt;
+mod cleanup;
 mod container;
+mod containerd;
+mod diagnostics;
+mod doctor;
+mod export;
+#[cfg(test)]
+mod fixtures;
 mod image;
+mod journal;
+mod mountinfo;
 mod overlay2;
+mod repair;
+mod safety;
+mod size;
+mod trash;
 mod ui;
 mod node;
 
@@ -12,24 +26,212 @@ fn main() -> anyhow::Result<()> {
     let matches = Command::new("docker-cleaner")
         .arg(Arg::new("delete")
             .long("delete")
-            .help("Propose to delete files/directories one by one"))
+            .action(ArgAction::SetTrue)
+            .help("Delete every dangling node without the interactive UI"))
         .arg(Arg::new("dry-run")
             .long("dry-run")
+            .action(ArgAction::SetTrue)
             .help("Display what would happen without actually deleting"))
+        .arg(Arg::new("force")
+            .long("force")
+            .global(true)
+            .action(ArgAction::SetTrue)
+            .help("Delete even if the docker daemon seems to be running"))
+        .arg(Arg::new("quarantine")
+            .long("quarantine")
+            .action(ArgAction::SetTrue)
+            .help("Move deleted files under <base>/.docker-cleaner-trash instead of removing them, with the overlay2 store only"))
+        .arg(Arg::new("journal")
+            .long("journal")
+            .value_name("FILE")
+            .global(true)
+            .help("Journal of the deletions, one JSON object per line [default: <base>/.docker-cleaner-journal.jsonl]"))
+        .arg(Arg::new("output")
+            .long("output")
+            .value_name("FORMAT")
+            .value_parser(["json", "dot"])
+            .help("Print the dependency graph in the given format instead of starting the UI"))
+        .arg(Arg::new("from")
+            .long("from")
+            .value_name("NODE_ID")
+            .requires("output")
+            .help("With --output dot, only print the nodes reachable from this node, e.g. Container:<id>"))
         .arg(Arg::new("base")
             .long("base")
             .value_name("PATH")
             .default_value("/var/lib/docker")
+            .global(true)
             .help("Base directory for Docker data"))
+        .arg(Arg::new("store")
+            .long("store")
+            .value_name("STORE")
+            .value_parser(["overlay2", "containerd"])
+            .default_value("overlay2")
+            .global(true)
+            .help("Image store of the daemon: the classic overlay2 one, or containerd when the containerd-snapshotter feature is on"))
+        .arg(Arg::new("containerd-root")
+            .long("containerd-root")
+            .value_name("PATH")
+            .default_value("/var/lib/containerd")
+            .global(true)
+            .help("Root directory of containerd, with --store containerd"))
+        .subcommand(Command::new("restore")
+            .about("Put back the files of a quarantine, or list the quarantines when no name is given")
+            .arg(Arg::new("name")
+                .value_name("NAME")
+                .help("Quarantine to restore, as listed without NAME")))
+        .subcommand(Command::new("journal")
+            .about("List the deletions recorded in journals")
+            .arg(Arg::new("summary")
+                .long("summary")
+                .action(ArgAction::SetTrue)
+                .help("Summarize each run instead of listing every node"))
+            .arg(Arg::new("files")
+                .value_name("FILE")
+                .num_args(0..)
+                .help("Journals to read, the --journal one by default")))
+        .subcommand(Command::new("doctor")
+            .about("Report dangling, missing and corrupt nodes, exits with 0 when healthy, 1 on warnings, 2 on errors"))
+        .subcommand(Command::new("explain")
+            .about("Print the tree of rdeps keeping a node alive, up to the images and containers")
+            .arg(Arg::new("node")
+                .value_name("NODE_ID")
+                .required(true)
+                .help("Node to explain, e.g. Overlay2:<id>")))
+        .subcommand(Command::new("repair")
+            .about("Find image layers whose overlay2 directory is missing or incomplete, and broken overlay2 short links")
+            .arg(Arg::new("apply")
+                .long("apply")
+                .action(ArgAction::SetTrue)
+                .help("Remove the broken layers, with their images and tags, so docker pulls them again, and fix the short links")))
         .get_matches();
 
-    let base_path = PathBuf::from(matches.get_one::<String>("base").unwrap());
-   // let delete_mode = matches.contains_id("delete");
-   // let dry_run = matches.contains_id("dry-run");
+    // node paths are compared with the mount points, a relative or symlinked base would never match
+    let base_path = mountinfo::canonical_path(&PathBuf::from(matches.get_one::<String>("base").unwrap()));
+    let delete_mode = matches.get_flag("delete");
+    let dry_run = matches.get_flag("dry-run");
+    let force = matches.get_flag("force");
+    let containerd_path = match matches.get_one::<String>("store").map(String::as_str) {
+        Some("containerd") => Some(PathBuf::from(matches.get_one::<String>("containerd-root").unwrap())),
+        _ => None,
+    };
+    // the trash moves files within the docker base, containerd keeps its own under another root
+    if containerd_path.is_some() && matches.get_flag("quarantine") {
+        anyhow::bail!("--quarantine cannot be used with --store containerd, the trash only holds files under {}", base_path.display());
+    }
+    let journal_path = match matches.get_one::<String>("journal") {
+        Some(path) => PathBuf::from(path),
+        None => base_path.join(journal::DEFAULT_JOURNAL),
+    };
+
+    if let Some(journal_matches) = matches.subcommand_matches("journal") {
+        let files: Vec<PathBuf> = match journal_matches.get_many::<String>("files") {
+            Some(files) => files.map(PathBuf::from).collect(),
+            None => vec![journal_path],
+        };
+        let mut entries = Vec::new();
+        for file in files {
+            entries.extend(journal::read_journal(&file)?);
+        }
+        if journal_matches.get_flag("summary") {
+            journal::print_summary(&entries);
+        } else {
+            journal::print_entries(&entries);
+        }
+        return Ok(());
+    }
+
+    if let Some(restore_matches) = matches.subcommand_matches("restore") {
+        return match restore_matches.get_one::<String>("name") {
+            Some(name) => {
+                safety::ensure_daemon_stopped(&base_path, containerd_path.as_deref(), force)?;
+                trash::restore(&base_path, name)
+            }
+            None => {
+                for name in trash::list_quarantines(&base_path)? {
+                    println!("{}", name);
+                }
+                Ok(())
+            }
+        };
+    }
+
+    let delete_options = analysis::DeleteOptions {
+        base_path: base_path.clone(),
+        containerd_path: containerd_path.clone(),
+        force,
+        remover: if matches.get_flag("quarantine") {
+            trash::Remover::Quarantine(trash::Trash::new(&base_path))
+        } else {
+            trash::Remover::Delete
+        },
+        journal: journal::Journal::new(&journal_path),
+    };
+
+    let store = match containerd_path {
+        Some(containerd_path) => analysis::Store::Containerd(containerd_path),
+        None => analysis::Store::Overlay2,
+    };
+    let mut graph = analysis::build_graph(&base_path, &store)?;
+
+    if matches.subcommand_matches("doctor").is_some() {
+        let verdict = doctor::run_doctor(&graph);
+        std::process::exit(verdict.exit_code());
+    }
+
+    if let Some(repair_matches) = matches.subcommand_matches("repair") {
+        let repairs = repair::find_repairs(&graph);
+        repair::print_repairs(&repairs);
+        let link_repairs = repair::find_link_repairs(&graph);
+        repair::print_link_repairs(&link_repairs);
+        if !repair_matches.get_flag("apply") || (repairs.is_empty() && link_repairs.is_empty()) {
+            return Ok(());
+        }
+        let mut results = repair::apply_repairs(&mut graph, &repairs, &delete_options);
+        // the layer repairs may have removed overlays, the links are checked again
+        let link_repairs = repair::find_link_repairs(&graph);
+        results.extend(repair::apply_link_repairs(&mut graph, &link_repairs, &delete_options));
+        let mut failed = 0;
+        for (node_id, error) in results {
+            match error {
+                Some(error) => {
+                    eprintln!("error: {} not repaired: {}", node_id, error);
+                    failed += 1;
+                }
+                None => println!("repaired {}", node_id),
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{} repair(s) failed", failed);
+        }
+        return Ok(());
+    }
+
+    if let Some(explain_matches) = matches.subcommand_matches("explain") {
+        let node_id = explain_matches.get_one::<String>("node").unwrap();
+        let node = analysis::find_node(&graph, node_id)
+            .ok_or_else(|| anyhow::anyhow!("Node {} not found", node_id))?;
+        for line in analysis::explain_tree(&node) {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+
+    if let Some(format) = matches.get_one::<String>("output") {
+        let mut stdout = std::io::stdout().lock();
+        match format.as_str() {
+            "json" => export::write_json(&graph, &base_path, &mut stdout)?,
+            "dot" => export::write_dot(&graph, matches.get_one::<String>("from").map(String::as_str), &mut stdout)?,
+            _ => unreachable!(),
+        }
+        return Ok(());
+    }
+
+    if delete_mode || dry_run {
+        return cleanup::run_cleanup(&mut graph, dry_run, &delete_options);
+    }
 
-    let graph = analysis::build_graph(&base_path)?;
-    
-    ui::run_ui(graph, base_path)?;
+    ui::run_ui(graph, delete_options, store)?;
 
     Ok(())
-}
\ No newline at end of file
+}
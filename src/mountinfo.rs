@@ -0,0 +1,90 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MOUNTINFO_PATH: &str = "/proc/self/mountinfo";
+
+pub struct MountInfo {
+    pub mount_point: PathBuf,
+    pub fs_type: String,
+}
+
+/// Parses the content of /proc/<pid>/mountinfo, malformed lines are skipped
+pub fn parse_mountinfo(content: &str) -> Vec<MountInfo> {
+    content.lines()
+        .filter_map(|line| {
+            // optional fields, like shared:N, end with a lone "-"
+            let (mount_fields, fs_fields) = line.split_once(" - ")?;
+            let mount_point = mount_fields.split(' ').nth(4)?;
+            let fs_type = fs_fields.split(' ').next()?;
+            Some(MountInfo {
+                mount_point: PathBuf::from(unescape(mount_point)),
+                fs_type: unescape(fs_type),
+            })
+        })
+        .collect()
+}
+
+/// Mounts of the current process, empty if /proc is not available
+pub fn read_mountinfo() -> Vec<MountInfo> {
+    fs::read_to_string(MOUNTINFO_PATH)
+        .map(|content| parse_mountinfo(&content))
+        .unwrap_or_default()
+}
+
+pub fn mount_points(mounts: &[MountInfo]) -> HashSet<PathBuf> {
+    mounts.iter().map(|mount| mount.mount_point.clone()).collect()
+}
+
+/// `path` as the kernel writes it in mountinfo: absolute, with its symlinks resolved.
+/// Left as is when it cannot be resolved, like a base directory that does not exist.
+pub fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// the kernel escapes space, tab, newline and backslash as \ooo octal sequences
+fn unescape(field: &str) -> String {
+    let bytes = field.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| (b'0'..=b'7').contains(b)) {
+            let value = bytes[i + 1..i + 4].iter().fold(0u32, |acc, b| acc * 8 + (b - b'0') as u32);
+            result.push(value as u8);
+            i += 4;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/mountinfo");
+
+    #[test]
+    fn parses_overlay_mounts() {
+        let mounts = parse_mountinfo(FIXTURE);
+        assert_eq!(mounts.len(), 6);
+
+        let overlay = mounts.iter().find(|mount| mount.fs_type == "overlay").unwrap();
+        assert_eq!(overlay.mount_point, Path::new("/var/lib/docker/overlay2/5a9c1e0d4b6f7a3e2c8d9b0a1f2e3d4c5b6a7980f1e2d3c4b5a6978877665544/merged"));
+    }
+
+    #[test]
+    fn unescapes_mount_points() {
+        let points = mount_points(&parse_mountinfo(FIXTURE));
+        assert!(points.contains(Path::new("/mnt/with space")));
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let mounts = parse_mountinfo("garbage\n28 1 8:1 / / rw - ext4 /dev/sda1 rw\n");
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].mount_point, Path::new("/"));
+    }
+}
@@ -1,5 +1,9 @@
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::rc::Rc;
+use crate::size::disk_usage;
+use crate::trash::Remover;
 
 pub trait StaticId {
     fn static_id(id: &str) -> String;
@@ -11,7 +15,33 @@ pub trait Node {
     fn deps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>>;
     fn rdeps(&self) -> &Vec<Rc<RefCell<dyn Node>>>;
     fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>>;
-    fn delete(&self) -> anyhow::Result<()>;
+    /// Files and directories touched by `delete`
+    fn paths(&self) -> Vec<PathBuf>;
+    /// Bytes used on disk by `paths`, inodes already in `seen` are not counted again
+    fn size(&self, seen: &mut HashSet<(u64, u64)>) -> u64 {
+        disk_usage(&self.paths(), seen)
+    }
+    /// Why the node is used by the running system, like an overlay currently mounted or a blob containerd records
+    fn in_use_reason(&self) -> Option<String> {
+        None
+    }
+    /// Whether the node is used by the running system, it is kept even if nothing depends on it
+    fn in_use(&self) -> bool {
+        self.in_use_reason().is_some()
+    }
+    /// Text shown in the lists: the id, followed by what helps recognizing the node, like a container name
+    fn label(&self) -> String {
+        self.id()
+    }
+    /// Extra lines shown with the node details
+    fn details(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// What keeps docker from using the node, like a layer without its `committed` marker
+    fn problems(&self) -> Vec<String> {
+        Vec::new()
+    }
+    fn delete(&self, remover: &Remover) -> anyhow::Result<()>;
 }
 
 #[derive(Debug)]
@@ -52,7 +82,83 @@ impl Node for MissingNode {
         &mut self.rdeps
     }
 
-    fn delete(&self) -> anyhow::Result<()> {
+    fn paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    fn delete(&self, _remover: &Remover) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn link(node: &Rc<RefCell<dyn Node>>, dep: &Rc<RefCell<dyn Node>>) {
+    node.borrow_mut().deps_mut().push(Rc::clone(dep));
+    dep.borrow_mut().rdeps_mut().push(Rc::clone(node));
+}
+
+/// Links `node` to `dep_id`, a MissingNode stands for it when it is not in the graph.
+/// The MissingNode is keyed by its own id, and shared by every node referencing the same `dep_id`.
+pub fn link_or_missing(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, node: &Rc<RefCell<dyn Node>>, dep_id: &str) {
+    let missing_id = format!("MissingNode:{}", dep_id);
+    if let Some(dep) = graph.get(dep_id).or(graph.get(&missing_id)).map(Rc::clone) {
+        link(node, &dep);
+        return;
+    }
+    let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
+        id: dep_id.to_string(),
+        deps: Vec::new(),
+        rdeps: vec![Rc::clone(node)],
+    }));
+    node.borrow_mut().deps_mut().push(Rc::clone(&missing_node));
+    graph.insert(missing_id, missing_node);
+}
+
+/// A file or directory the analyzers could not read or parse.
+/// Its rdep is the node the artifact belongs to, when there is one.
+pub struct CorruptNode {
+    pub path: PathBuf,
+    pub error: String,
+    pub deps: Vec<Rc<RefCell<dyn Node>>>,
+    pub rdeps: Vec<Rc<RefCell<dyn Node>>>,
+}
+
+impl StaticId for CorruptNode {
+    fn static_id(id: &str) -> String {
+        format!("Corrupt:{}", id)
+    }
+}
+
+impl Node for CorruptNode {
+    fn id(&self) -> String {
+        Self::static_id(&self.path.to_string_lossy())
+    }
+
+    fn deps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.deps
+    }
+
+    fn deps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.deps
+    }
+
+    fn rdeps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.rdeps
+    }
+
+    fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.rdeps
+    }
+
+    // the artifact is part of another node, deleting it alone would not help
+    fn paths(&self) -> Vec<PathBuf> {
+        Vec::new()
+    }
+
+    fn details(&self) -> Vec<String> {
+        vec![format!("Error: {}", self.error)]
+    }
+
+    fn delete(&self, _remover: &Remover) -> anyhow::Result<()> {
         Ok(())
     }
-}
\ No newline at end of file
+}
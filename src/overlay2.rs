@@ -1,17 +1,57 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::fs;
 use std::rc::Rc;
 use std::cell::RefCell;
 use anyhow::{Result, Context};
-use crate::node::{Node, MissingNode};
+use crate::diagnostics::{read_dir_entries, record_corrupt};
+use crate::node::{link_or_missing, Node, StaticId};
+use crate::trash::Remover;
 
 pub struct Overlay2Node {
     id: String,
-  //  short_link: String,
+    short_link: Option<String>,
     deps: Vec<Rc<RefCell<dyn Node>>>,
     rdeps: Vec<Rc<RefCell<dyn Node>>>,
     path: PathBuf,
+    mounted: bool,
+    inventory: Overlay2Inventory,
+}
+
+/// Which of the files docker keeps in a layer dir exist.
+/// `work` and `merged` only exist for layers that were mounted, `lower` not for a base layer.
+#[derive(Default)]
+struct Overlay2Inventory {
+    diff: bool,
+    work: bool,
+    merged: bool,
+    committed: bool,
+    lower: bool,
+    link: bool,
+}
+
+impl Overlay2Inventory {
+    fn scan(path: &Path) -> Overlay2Inventory {
+        Overlay2Inventory {
+            diff: path.join("diff").is_dir(),
+            work: path.join("work").is_dir(),
+            merged: path.join("merged").is_dir(),
+            committed: path.join("committed").is_file(),
+            lower: path.join("lower").is_file(),
+            link: path.join("link").is_file(),
+        }
+    }
+
+    fn entries(&self) -> [(&'static str, bool); 6] {
+        [
+            ("diff", self.diff),
+            ("work", self.work),
+            ("merged", self.merged),
+            ("committed", self.committed),
+            ("lower", self.lower),
+            ("link", self.link),
+        ]
+    }
 }
 
 impl Node for Overlay2Node {
@@ -35,76 +75,318 @@ impl Node for Overlay2Node {
         &mut self.rdeps
     }
 
-    fn delete(&self) -> Result<()> {
-        fs::remove_dir_all(&self.path).context("Failed to remove overlay2 directory")
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    fn in_use_reason(&self) -> Option<String> {
+        self.mounted.then(|| "merged directory is mounted".to_string())
+    }
+
+    fn label(&self) -> String {
+        if self.problems().is_empty() {
+            self.id()
+        } else {
+            format!("{} [incomplete]", self.id())
+        }
+    }
+
+    fn details(&self) -> Vec<String> {
+        let mut details = Vec::new();
+        if let Some(short_link) = &self.short_link {
+            details.push(format!("Short link: {}", short_link));
+        }
+        let names = |present: bool| self.inventory.entries().iter()
+            .filter(|(_, exists)| *exists == present)
+            .map(|(name, _)| *name)
+            .collect::<Vec<&str>>()
+            .join(", ");
+        details.push(format!("Present: {}", names(true)));
+        details.push(format!("Absent: {}", names(false)));
+        details.extend(self.problems().iter().map(|problem| format!("Incomplete: {}", problem)));
+        details
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !self.inventory.diff {
+            problems.push("no diff".to_string());
+        }
+        // docker writes committed once a pulled or built layer is registered in layerdb
+        let in_layerdb = self.rdeps.iter().any(|rdep| rdep.borrow().id().starts_with("ImageLayer:"));
+        if in_layerdb && !self.inventory.committed {
+            problems.push("no committed, but used by layerdb".to_string());
+        }
+        problems
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        remover.remove_dir_all(&self.path).context("Failed to remove overlay2 directory")
+    }
+}
+
+/// Entry of overlay2/l, the short names used in `lower` to keep the mount options short.
+/// Should point to `../<id>/diff` of the overlay whose `link` file names it.
+pub struct ShortLinkNode {
+    short_link: String,
+    deps: Vec<Rc<RefCell<dyn Node>>>,
+    rdeps: Vec<Rc<RefCell<dyn Node>>>,
+    path: PathBuf,
+    /// None when the entry is not a symlink
+    target: Option<PathBuf>,
+}
+
+impl StaticId for ShortLinkNode {
+    fn static_id(short_link: &str) -> String {
+        format!("ShortLink:{}", short_link)
+    }
+}
+
+impl ShortLinkNode {
+    fn target_exists(&self) -> bool {
+        match (&self.target, self.path.parent()) {
+            (Some(target), Some(links_path)) => links_path.join(target).exists(),
+            _ => false,
+        }
     }
 }
 
-pub fn analyze_overlay2(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>) -> Result<()> {
+/// Target a short link must have for the overlay `id`
+pub fn short_link_target(id: &str) -> PathBuf {
+    Path::new("..").join(id).join("diff")
+}
+
+impl Node for ShortLinkNode {
+    fn id(&self) -> String {
+        ShortLinkNode::static_id(&self.short_link)
+    }
+
+    fn deps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.deps
+    }
+
+    fn deps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.deps
+    }
+
+    fn rdeps(&self) -> &Vec<Rc<RefCell<dyn Node>>> {
+        &self.rdeps
+    }
+
+    fn rdeps_mut(&mut self) -> &mut Vec<Rc<RefCell<dyn Node>>> {
+        &mut self.rdeps
+    }
+
+    fn paths(&self) -> Vec<PathBuf> {
+        vec![self.path.clone()]
+    }
+
+    fn label(&self) -> String {
+        match &self.target {
+            Some(target) => format!("{} -> {}", self.id(), target.display()),
+            None => self.id(),
+        }
+    }
+
+    fn details(&self) -> Vec<String> {
+        let mut details = vec![match &self.target {
+            Some(target) if self.target_exists() => format!("Target: {}", target.display()),
+            Some(target) => format!("Target: {} (does not exist)", target.display()),
+            None => "Target: none, not a symlink".to_string(),
+        }];
+        for rdep in &self.rdeps {
+            let rdep_id = rdep.borrow().id();
+            if let Some(id) = rdep_id.strip_prefix("Overlay2:") {
+                let expected = short_link_target(id);
+                if self.target.as_ref() != Some(&expected) {
+                    details.push(format!("Expected: {} (link of {})", expected.display(), rdep_id));
+                }
+            }
+        }
+        details
+    }
+
+    // the overlays naming the link in their lower cannot be mounted
+    fn problems(&self) -> Vec<String> {
+        if self.rdeps.is_empty() || self.target_exists() {
+            return Vec::new();
+        }
+        vec!["target does not exist, but used by overlay2".to_string()]
+    }
+
+    fn delete(&self, remover: &Remover) -> Result<()> {
+        remover.remove_file(&self.path).context("Failed to remove overlay2 short link")
+    }
+}
+
+pub fn analyze_overlay2(base_path: &Path, graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, mount_points: &HashSet<PathBuf>) -> Result<()> {
     let overlay2_path = base_path.join("overlay2");
-    let mut layer_map_id_to_short_link = HashMap::new();
+    let mut layer_ids = Vec::new();
     let mut layer_map_short_link_to_id = HashMap::new();
 
     // Step 1: Build layer maps
-    for entry in fs::read_dir(&overlay2_path)? {
-        let entry = entry?;
+    for entry in read_dir_entries(graph, &overlay2_path) {
         let path = entry.path();
         if path.is_dir() {
             let id = path.file_name().unwrap().to_string_lossy().into_owned();
             let link_path = path.join("link");
             if link_path.exists() {
                 let overlay2_id = format!("Overlay2:{}", &id);
-                let short_link = fs::read_to_string(link_path)?.trim().to_string();
-                layer_map_id_to_short_link.insert(id.clone(), short_link.clone());
-                layer_map_short_link_to_id.insert(short_link.clone(), id);
-
-                let node = Rc::new(RefCell::new(Overlay2Node {
+                let overlay2_node = Rc::new(RefCell::new(Overlay2Node {
                     id: overlay2_id.clone(),
-            //        short_link,
+                    short_link: None,
                     deps: Vec::new(),
                     rdeps: Vec::new(),
+                    mounted: mount_points.contains(&path.join("merged")),
+                    inventory: Overlay2Inventory::scan(&path),
                     path,
                 }));
+                let node: Rc<RefCell<dyn Node>> = overlay2_node.clone();
+
+                match fs::read_to_string(&link_path) {
+                    Ok(short_link) => {
+                        let short_link = short_link.trim().to_string();
+                        layer_map_short_link_to_id.insert(short_link.clone(), id.clone());
+                        overlay2_node.borrow_mut().short_link = Some(short_link);
+                    }
+                    Err(e) => record_corrupt(graph, &link_path, &e, Some(&node)),
+                }
+                layer_ids.push(id);
                 graph.insert(overlay2_id, node);
             }
         }
     }
 
-    // Step 2: Build the graph
-    for (id, _short_link) in &layer_map_id_to_short_link {
-        let overlay2_id = format!("Overlay2:{}", id); 
+    // Step 2: Short links, each one is a dep of the overlay naming it in its link file,
+    // those no overlay names are left dangling
+    let links_path = overlay2_path.join("l");
+    for entry in read_dir_entries(graph, &links_path) {
+        let node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(ShortLinkNode {
+            short_link: entry.file_name().to_string_lossy().into_owned(),
+            deps: Vec::new(),
+            rdeps: Vec::new(),
+            target: fs::read_link(entry.path()).ok(),
+            path: entry.path(),
+        }));
+        let node_id = node.borrow().id();
+        graph.insert(node_id, node);
+    }
+    for (short_link, id) in &layer_map_short_link_to_id {
+        let node = match graph.get(&format!("Overlay2:{}", id)).map(Rc::clone) {
+            Some(node) => node,
+            None => continue,
+        };
+        link_or_missing(graph, &node, &ShortLinkNode::static_id(short_link));
+    }
+
+    // Step 3: Build the graph
+    for id in &layer_ids {
+        let overlay2_id = format!("Overlay2:{}", id);
         let node = match graph.get(&overlay2_id).map(Rc::clone) {
             Some(node) => node,
             None => continue,
         };
 
-        let path = overlay2_path.join(&id);
+        let path = overlay2_path.join(id);
         let lower_path = path.join("lower");
 
         if lower_path.exists() {
-            let lower_content = fs::read_to_string(lower_path)?;
+            let lower_content = match fs::read_to_string(&lower_path) {
+                Ok(lower_content) => lower_content,
+                Err(e) => {
+                    record_corrupt(graph, &lower_path, &e, Some(&node));
+                    continue;
+                }
+            };
             for lower_short_link in lower_content.split(':') {
-                let lower_short_id = lower_short_link.trim_start_matches("l/").to_string();
-                if let Some(lower_id) = layer_map_short_link_to_id.get(&lower_short_id) {
-                    let lower_node_id = format!("Overlay2:{}", lower_id);
-                    if let Some(lower_node) = graph.get(&lower_node_id) {
-                        (*node).borrow_mut().deps_mut().push(Rc::clone(lower_node));
-                        (**lower_node).borrow_mut().rdeps_mut().push(Rc::clone(&node));
-                    } else {
-                        let mut rdeps = Vec::new();
-                        rdeps.push(Rc::clone(&node));
-                        let missing_node: Rc<RefCell<dyn Node>> = Rc::new(RefCell::new(MissingNode {
-                            id: lower_id.clone(),
-                            deps: Vec::new(),
-                            rdeps,
-                        }));
-                        (*node).borrow_mut().deps_mut().push(Rc::clone(&missing_node));
-                        graph.insert(lower_node_id, missing_node);
-                    }
+                let lower_short_id = lower_short_link.trim().trim_start_matches("l/").to_string();
+                if lower_short_id.is_empty() {
+                    continue;
                 }
+                let lower_id = match layer_map_short_link_to_id.get(&lower_short_id) {
+                    Some(lower_id) => lower_id,
+                    None => {
+                        // no overlay has this short link anymore, the lower layer is gone,
+                        // unless the link is still in l/
+                        link_or_missing(graph, &node, &ShortLinkNode::static_id(&lower_short_id));
+                        continue;
+                    }
+                };
+                link_or_missing(graph, &node, &format!("Overlay2:{}", lower_id));
             }
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{analyze, dep_ids, image_store, overlay};
+    use crate::mountinfo::canonical_path;
+
+    #[test]
+    fn mounted_overlay_under_symlinked_base_is_in_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("data/docker");
+        overlay(&real, "cache0", "SHORT0", &[]);
+        let base = dir.path().join("docker");
+        std::os::unix::fs::symlink(&real, &base).unwrap();
+
+        // the kernel shows the resolved path
+        let mount_points = HashSet::from([fs::canonicalize(&real).unwrap().join("overlay2/cache0/merged")]);
+        let mut graph = HashMap::new();
+        analyze_overlay2(&canonical_path(&base), &mut graph, &mount_points).unwrap();
+        assert!(graph.get("Overlay2:cache0").unwrap().borrow().in_use());
+    }
+
+    #[test]
+    fn unknown_lower_short_link_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        overlay(base, "cache0", "SHORT0", &[]);
+        overlay(base, "cache1", "SHORT1", &["SHORT0", "GONE"]);
+
+        let mut graph = HashMap::new();
+        analyze_overlay2(base, &mut graph, &HashSet::new()).unwrap();
+        let missing = graph.get("MissingNode:ShortLink:GONE").unwrap().borrow();
+        let rdep_ids: Vec<String> = missing.rdeps().iter().map(|rdep| rdep.borrow().id()).collect();
+        assert_eq!(rdep_ids, vec!["Overlay2:cache1".to_string()]);
+        assert!(dep_ids(&graph, "Overlay2:cache1").contains(&"Overlay2:cache0".to_string()));
+    }
+
+    #[test]
+    fn lower_short_link_left_in_l_is_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        overlay(base, "cache1", "SHORT1", &["SHORT0"]);
+        std::os::unix::fs::symlink(short_link_target("cache0"), base.join("overlay2/l/SHORT0")).unwrap();
+
+        let mut graph = HashMap::new();
+        analyze_overlay2(base, &mut graph, &HashSet::new()).unwrap();
+        assert!(!graph.contains_key("MissingNode:ShortLink:SHORT0"));
+        let link = graph.get("ShortLink:SHORT0").unwrap().borrow();
+        assert_eq!(link.rdeps().len(), 1);
+        // cache0 is gone, so the link leads nowhere
+        assert_eq!(link.problems(), vec!["target does not exist, but used by overlay2".to_string()]);
+    }
+
+    #[test]
+    fn overlay_without_committed_is_incomplete() {
+        let dir = image_store();
+        fs::remove_file(dir.path().join("overlay2/cache2/committed")).unwrap();
+        // docker writes committed once the layer is in layerdb, so it is only missing for those
+        overlay(dir.path(), "build", "BUILD", &[]);
+
+        let graph = analyze(dir.path());
+        assert!(graph.get("Overlay2:build").unwrap().borrow().problems().is_empty());
+        let overlay = graph.get("Overlay2:cache2").unwrap().borrow();
+        assert_eq!(overlay.problems(), vec!["no committed, but used by layerdb".to_string()]);
+        assert_eq!(overlay.label(), "Overlay2:cache2 [incomplete]");
+        assert!(overlay.details().contains(&"Absent: work, merged, committed, lower".to_string()));
+        let complete = graph.get("Overlay2:cache1").unwrap().borrow();
+        assert!(complete.problems().is_empty());
+        assert_eq!(complete.label(), "Overlay2:cache1");
+    }
+}
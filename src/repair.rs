@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::cell::RefCell;
+use anyhow::{Result, Context};
+use crate::analysis::{classify_layers, dangling_nodes, find_node, remove_node, DeleteOptions, DeletionPlan};
+use crate::node::{Node, StaticId};
+use crate::overlay2::{short_link_target, ShortLinkNode};
+use crate::safety::ensure_daemon_stopped;
+
+/// Fix for an image layer whose overlay2 directory is gone or was never committed,
+/// which makes every later `docker pull` of the layer fail with
+/// "failed to register layer: ... committed: no such file or directory".
+/// The layerdb entry is removed with the images and tags built on it, so docker downloads them again.
+pub struct Repair {
+    pub layer_id: String,
+    pub reason: String,
+    /// Containers and mounts using the layer, the repair is refused while they exist
+    pub blockers: Vec<String>,
+    /// Nodes to remove, the images and tags before the layers they use
+    pub node_ids: Vec<String>,
+    /// Broken overlay left on disk, with the nodes still using it
+    pub kept: Option<(String, Vec<String>)>,
+}
+
+impl Repair {
+    pub fn plan(&self, graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> Result<DeletionPlan> {
+        if !self.blockers.is_empty() {
+            anyhow::bail!("{} is used by {}, remove them first", self.layer_id, self.blockers.join(", "));
+        }
+        let mut plan = DeletionPlan::for_roots(graph, &self.node_ids, false)?;
+        // only the tags and images on top are roots, every other node must have lost its rdeps
+        // to the previous steps, or something started using it since the repair was found
+        plan.root_ids.retain(|root_id| find_node(graph, root_id).is_some_and(|node| node.borrow().rdeps().is_empty()));
+        Ok(plan)
+    }
+}
+
+/// Why the overlay2 dep of an image layer cannot be used by docker, None if it is fine
+fn broken_overlay(layer: &Rc<RefCell<dyn Node>>) -> Option<String> {
+    for dep in layer.borrow().deps() {
+        let dep = dep.borrow();
+        let dep_id = dep.id();
+        if dep_id.starts_with("MissingNode:Overlay2:") {
+            return Some(format!("{} does not exist", dep_id.trim_start_matches("MissingNode:")));
+        }
+        if let Some(problem) = dep.problems().first() {
+            return Some(format!("{} has {}", dep_id, problem));
+        }
+    }
+    None
+}
+
+/// Nodes the broken layer takes down with it: the layers built on it, their images and tags.
+/// Sorted so each node comes before the nodes it depends on.
+fn dependents(layer: &Rc<RefCell<dyn Node>>) -> Vec<Rc<RefCell<dyn Node>>> {
+    let mut found: Vec<Rc<RefCell<dyn Node>>> = vec![Rc::clone(layer)];
+    let mut ids: HashSet<String> = HashSet::from([layer.borrow().id()]);
+    let mut index = 0;
+    while index < found.len() {
+        let rdeps: Vec<Rc<RefCell<dyn Node>>> = found[index].borrow().rdeps().iter().map(Rc::clone).collect();
+        for rdep in rdeps {
+            if ids.insert(rdep.borrow().id()) {
+                found.push(rdep);
+            }
+        }
+        index += 1;
+    }
+
+    // a node is ordered once all its rdeps are
+    let mut ordered: Vec<Rc<RefCell<dyn Node>>> = Vec::new();
+    let mut ordered_ids = HashSet::new();
+    while ordered.len() < found.len() {
+        let ready: Vec<Rc<RefCell<dyn Node>>> = found.iter()
+            .filter(|node| !ordered_ids.contains(&node.borrow().id()))
+            .filter(|node| node.borrow().rdeps().iter().all(|rdep| ordered_ids.contains(&rdep.borrow().id())))
+            .map(Rc::clone)
+            .collect();
+        if ready.is_empty() {
+            break;
+        }
+        for node in ready {
+            ordered_ids.insert(node.borrow().id());
+            ordered.push(node);
+        }
+    }
+    ordered
+}
+
+pub fn find_repairs(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> Vec<Repair> {
+    let mut layers = classify_layers(graph).remove("ImageLayer").unwrap_or_default();
+    layers.sort_by_key(|layer| layer.borrow().id());
+
+    let mut repairs: Vec<Repair> = Vec::new();
+    for layer in &layers {
+        let reason = match broken_overlay(layer) {
+            Some(reason) => reason,
+            None => continue,
+        };
+        let nodes = dependents(layer);
+        let mut node_ids: Vec<String> = nodes.iter().map(|node| node.borrow().id()).collect();
+        let blockers: Vec<String> = node_ids.iter()
+            .filter(|node_id| node_id.starts_with("Container:") || node_id.starts_with("Mount:"))
+            .cloned()
+            .collect();
+        // the missing or broken overlay is only there because of the layer, it goes with it,
+        // along with its own short link
+        let mut kept = None;
+        for dep in layer.borrow().deps() {
+            let dep = dep.borrow();
+            let dep_id = dep.id();
+            let broken = dep_id.starts_with("Overlay2:") && !dep.problems().is_empty();
+            if !broken && !dep_id.starts_with("MissingNode:") {
+                continue;
+            }
+            if dep.rdeps().len() == 1 {
+                node_ids.push(dep_id);
+                for link in dep.deps() {
+                    let link_id = link.borrow().id();
+                    if link_id.starts_with("ShortLink:") && link.borrow().rdeps().len() == 1 {
+                        node_ids.push(link_id);
+                    }
+                }
+            } else if broken {
+                let users = dep.rdeps().iter().map(|rdep| rdep.borrow().id()).filter(|rdep_id| !node_ids.contains(rdep_id)).collect();
+                kept = Some((dep_id, users));
+            }
+        }
+        repairs.push(Repair { layer_id: layer.borrow().id(), reason, blockers, node_ids, kept });
+    }
+
+    // a broken layer built on another broken layer is removed by the repair of the lower one
+    let covered: HashSet<String> = repairs.iter()
+        .flat_map(|repair| repair.node_ids.iter().filter(|node_id| **node_id != repair.layer_id).cloned())
+        .collect();
+    repairs.retain(|repair| !covered.contains(&repair.layer_id));
+    repairs
+}
+
+/// Applies the repairs, each one stops at its first error and a blocked one fails.
+/// Returns the layer id and the error of each repair, None when it was applied.
+pub fn apply_repairs(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, repairs: &[Repair], options: &DeleteOptions) -> Vec<(String, Option<String>)> {
+    repairs.iter()
+        .map(|repair| {
+            let result = repair.plan(graph).and_then(|plan| remove_node(graph, &plan, options)).map(print_warnings);
+            (repair.layer_id.clone(), result.err().map(|e| format!("{:#}", e)))
+        })
+        .collect()
+}
+
+fn print_warnings(warnings: Vec<String>) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+pub fn print_repairs(repairs: &[Repair]) {
+    if repairs.is_empty() {
+        println!("No broken image layer found");
+        return;
+    }
+    for repair in repairs {
+        println!("{}: {}", repair.layer_id, repair.reason);
+        if repair.blockers.is_empty() {
+            for node_id in &repair.node_ids {
+                println!("    remove {}", node_id);
+            }
+            // docker registers the layer again in a new overlay2 dir, this one is only disk space
+            if let Some((overlay_id, users)) = &repair.kept {
+                println!("    keep {}, still used by {}, it is dangling once they are removed", overlay_id, users.join(", "));
+            }
+        } else {
+            println!("    used by {}, remove them first", repair.blockers.join(", "));
+        }
+    }
+}
+
+/// Fix for an entry of overlay2/l
+pub enum LinkRepair {
+    /// The short link of an overlay is missing or points elsewhere, it is written again
+    Create { overlay_id: String, link: PathBuf, target: PathBuf },
+    /// No overlay names the short link, it is removed
+    Remove { link_id: String },
+}
+
+impl LinkRepair {
+    pub fn id(&self) -> &str {
+        match self {
+            LinkRepair::Create { overlay_id, .. } => overlay_id,
+            LinkRepair::Remove { link_id } => link_id,
+        }
+    }
+}
+
+pub fn find_link_repairs(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> Vec<LinkRepair> {
+    let mut overlays = classify_layers(graph).remove("Overlay2").unwrap_or_default();
+    overlays.sort_by_key(|overlay| overlay.borrow().id());
+
+    let mut repairs = Vec::new();
+    for overlay in &overlays {
+        let overlay = overlay.borrow();
+        let overlay_id = overlay.id();
+        let overlay_path = overlay.paths().remove(0);
+        let links_path = overlay_path.parent().unwrap().join("l");
+        let target = short_link_target(overlay_id.trim_start_matches("Overlay2:"));
+        // the overlay deps also hold the short links of its lower layers, only its own one is checked
+        let short_link = match fs::read_to_string(overlay_path.join("link")) {
+            Ok(short_link) if !short_link.trim().is_empty() => short_link.trim().to_string(),
+            _ => continue,
+        };
+        let link = links_path.join(&short_link);
+        if fs::read_link(&link).ok().as_ref() != Some(&target) {
+            repairs.push(LinkRepair::Create { overlay_id, link, target });
+        }
+    }
+    for link in dangling_nodes(graph, "ShortLink") {
+        repairs.push(LinkRepair::Remove { link_id: link.borrow().id() });
+    }
+    repairs
+}
+
+/// Writes the short link, a wrong one is removed first.
+/// Both are journaled, an entry that cannot be written once the link is changed is returned as a warning.
+fn create_link(link: &Path, target: &Path, options: &DeleteOptions) -> Result<Vec<String>> {
+    ensure_daemon_stopped(&options.base_path, options.containerd_path.as_deref(), options.force)?;
+    options.journal.check()?;
+    let link_id = ShortLinkNode::static_id(&link.file_name().unwrap_or_default().to_string_lossy());
+
+    let mut entries = Vec::new();
+    if fs::symlink_metadata(link).is_ok() {
+        options.remover.remove_file(link).context("Failed to remove the wrong short link")?;
+        entries.push(options.journal.entry(&link_id, &[link.to_path_buf()], 0, None, options.remover.quarantine_root()));
+    }
+    symlink(target, link).context("Failed to create the short link")?;
+    entries.push(options.journal.created_entry(&link_id, link));
+
+    Ok(entries.iter()
+        .filter_map(|entry| options.journal.append(entry).err())
+        .map(|e| format!("{} changed but not journaled: {:#}", link_id, e))
+        .collect())
+}
+
+/// Same as `apply_repairs`, removed and created links are journaled like any deletion
+pub fn apply_link_repairs(graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>, repairs: &[LinkRepair], options: &DeleteOptions) -> Vec<(String, Option<String>)> {
+    repairs.iter()
+        .map(|repair| {
+            let result = match repair {
+                LinkRepair::Create { link, target, .. } => create_link(link, target, options).map(print_warnings),
+                LinkRepair::Remove { link_id } => DeletionPlan::new(graph, link_id, false)
+                    .and_then(|plan| remove_node(graph, &plan, options))
+                    .map(print_warnings),
+            };
+            (repair.id().to_string(), result.err().map(|e| format!("{:#}", e)))
+        })
+        .collect()
+}
+
+pub fn print_link_repairs(repairs: &[LinkRepair]) {
+    if repairs.is_empty() {
+        println!("No broken overlay2 short link found");
+        return;
+    }
+    for repair in repairs {
+        match repair {
+            LinkRepair::Create { overlay_id, link, target } => {
+                println!("{}: short link missing or wrong", overlay_id);
+                println!("    create {} -> {}", link.display(), target.display());
+            }
+            LinkRepair::Remove { link_id } => {
+                println!("{}: used by no overlay", link_id);
+                println!("    remove {}", link_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{analyze, delete_options, image_store, overlay, write, DIFF_IDS, IMAGE_ID};
+    use crate::image::{chain_ids, ImageContentNode, ImageLayerNode, ImageRepoNode, IMAGEDB_PATH};
+    use crate::journal::read_journal;
+    use crate::trash::{Remover, Trash};
+
+    #[test]
+    fn missing_link_is_created_and_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        overlay(base, "cache0", "SHORT0", &[]);
+        let link = base.join("overlay2/l/SHORT0");
+        fs::remove_file(&link).unwrap();
+
+        let mut graph = analyze(base);
+        let repairs = find_link_repairs(&graph);
+        assert_eq!(repairs.len(), 1);
+        assert!(matches!(&repairs[0], LinkRepair::Create { overlay_id, .. } if overlay_id == "Overlay2:cache0"));
+
+        let results = apply_link_repairs(&mut graph, &repairs, &delete_options(base, Remover::Delete));
+        assert_eq!(results, vec![("Overlay2:cache0".to_string(), None)]);
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../cache0/diff"));
+        let entries = read_journal(&base.join("journal")).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].created);
+        assert_eq!(entries[0].node_id, "ShortLink:SHORT0");
+        assert!(find_link_repairs(&analyze(base)).is_empty());
+    }
+
+    #[test]
+    fn wrong_link_is_quarantined_and_written_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        overlay(base, "cache0", "SHORT0", &[]);
+        let link = base.join("overlay2/l/SHORT0");
+        fs::remove_file(&link).unwrap();
+        symlink("../other/diff", &link).unwrap();
+
+        let mut graph = analyze(base);
+        let repairs = find_link_repairs(&graph);
+        assert!(matches!(&repairs[..], [LinkRepair::Create { target, .. }] if target == Path::new("../cache0/diff")));
+
+        let options = delete_options(base, Remover::Quarantine(Trash::new(base)));
+        apply_link_repairs(&mut graph, &repairs, &options);
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../cache0/diff"));
+        let entries = read_journal(&base.join("journal")).unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.created).collect::<Vec<bool>>(), vec![false, true]);
+        let Remover::Quarantine(trash) = &options.remover else { unreachable!() };
+        assert_eq!(fs::read_link(trash.root().join("overlay2/l/SHORT0")).unwrap(), Path::new("../other/diff"));
+    }
+
+    #[test]
+    fn orphan_link_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        overlay(base, "cache0", "SHORT0", &[]);
+        let orphan = base.join("overlay2/l/ORPHAN");
+        symlink("../gone/diff", &orphan).unwrap();
+
+        let mut graph = analyze(base);
+        let repairs = find_link_repairs(&graph);
+        assert!(matches!(&repairs[..], [LinkRepair::Remove { link_id }] if link_id == "ShortLink:ORPHAN"));
+
+        apply_link_repairs(&mut graph, &repairs, &delete_options(base, Remover::Delete));
+        assert!(fs::symlink_metadata(&orphan).is_err());
+        assert!(fs::symlink_metadata(base.join("overlay2/l/SHORT0")).is_ok());
+    }
+
+    #[test]
+    fn only_the_own_link_of_an_overlay_is_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        overlay(base, "cache0", "SHORT0", &[]);
+        overlay(base, "cache1", "SHORT1", &["SHORT0"]);
+        fs::remove_file(base.join("overlay2/l/SHORT0")).unwrap();
+        symlink("../cache1/diff", base.join("overlay2/l/SHORT0")).unwrap();
+
+        // SHORT0 is in the lower of cache1, but only cache0 owns it
+        let repairs = find_link_repairs(&analyze(base));
+        let ids: Vec<&str> = repairs.iter().map(LinkRepair::id).collect();
+        assert_eq!(ids, vec!["Overlay2:cache0"]);
+    }
+
+    #[test]
+    fn layer_without_overlay_is_repaired_with_what_uses_it() {
+        let dir = image_store();
+        assert!(find_repairs(&analyze(dir.path())).is_empty());
+
+        fs::remove_dir_all(dir.path().join("overlay2/cache1")).unwrap();
+        let graph = analyze(dir.path());
+        let repairs = find_repairs(&graph);
+        assert_eq!(repairs.len(), 1);
+
+        let chain_ids = chain_ids(&DIFF_IDS);
+        let repair = &repairs[0];
+        assert_eq!(repair.layer_id, ImageLayerNode::static_id(&chain_ids[1]));
+        assert!(repair.blockers.is_empty());
+        assert_eq!(repair.node_ids, vec![
+            ImageRepoNode::static_id("app:latest"),
+            ImageContentNode::static_id(IMAGE_ID),
+            ImageLayerNode::static_id(&chain_ids[2]),
+            ImageLayerNode::static_id(&chain_ids[1]),
+            "MissingNode:Overlay2:cache1".to_string(),
+        ]);
+        assert!(repair.plan(&graph).is_ok());
+    }
+
+    #[test]
+    fn uncommitted_overlay_is_repaired_unless_an_overlay_builds_on_it() {
+        let dir = image_store();
+        fs::remove_file(dir.path().join("overlay2/cache2/committed")).unwrap();
+        let repairs = find_repairs(&analyze(dir.path()));
+        assert_eq!(repairs[0].node_ids[3..], ["Overlay2:cache2".to_string(), "ShortLink:SHORT2".to_string()]);
+        assert!(repairs[0].kept.is_none());
+
+        let dir = image_store();
+        fs::remove_file(dir.path().join("overlay2/cache1/committed")).unwrap();
+        write(&dir.path().join("overlay2/cache2/lower"), "l/SHORT1:l/SHORT0");
+        let repairs = find_repairs(&analyze(dir.path()));
+        assert_eq!(repairs[0].node_ids.last().unwrap(), &ImageLayerNode::static_id(&chain_ids(&DIFF_IDS)[1]));
+        assert_eq!(repairs[0].kept, Some(("Overlay2:cache1".to_string(), vec!["Overlay2:cache2".to_string()])));
+    }
+
+    #[test]
+    fn stale_repair_does_not_remove_what_is_still_used() {
+        let dir = image_store();
+        fs::remove_dir_all(dir.path().join("overlay2/cache1")).unwrap();
+        let mut graph = analyze(dir.path());
+        let mut repair = find_repairs(&graph).remove(0);
+        // the tag is not part of the repair, so the image it names must stay
+        repair.node_ids.remove(0);
+
+        let options = delete_options(dir.path(), Remover::Delete);
+        let error = repair.plan(&graph).and_then(|plan| remove_node(&mut graph, &plan, &options)).unwrap_err();
+        assert!(error.to_string().contains("is still used"));
+        assert!(dir.path().join(IMAGEDB_PATH).join(IMAGE_ID).exists());
+    }
+}
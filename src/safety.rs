@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::Path;
+use anyhow::Result;
+use crate::mountinfo::read_mountinfo;
+
+const DOCKER_PID_FILE: &str = "/var/run/docker.pid";
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+const CONTAINERD_SOCKET: &str = "/run/containerd/containerd.sock";
+
+/// Reasons to think dockerd is running on top of `base_path`, or containerd on top of
+/// `containerd_path` with the containerd image store, empty if none was found
+pub fn daemon_activity(base_path: &Path, containerd_path: Option<&Path>) -> Vec<String> {
+    let mut reasons = Vec::new();
+
+    if let Ok(pid) = fs::read_to_string(DOCKER_PID_FILE) {
+        let pid = pid.trim();
+        if !pid.is_empty() && Path::new("/proc").join(pid).exists() {
+            reasons.push(format!("dockerd is running (pid {} from {})", pid, DOCKER_PID_FILE));
+        }
+    }
+
+    let pids = pids_named("dockerd");
+    if !pids.is_empty() {
+        reasons.push(format!("dockerd process(es) found: {}", pids.join(", ")));
+    }
+
+    // dockerd runs its own containerd with the overlay2 store, it only matters when it owns the images
+    if let Some(containerd_path) = containerd_path {
+        let pids = pids_named("containerd");
+        if !pids.is_empty() {
+            reasons.push(format!("containerd process(es) found: {}, using {}", pids.join(", "), containerd_path.display()));
+        }
+        if Path::new(CONTAINERD_SOCKET).exists() {
+            reasons.push(format!("containerd socket {} exists", CONTAINERD_SOCKET));
+        }
+    }
+
+    let overlay2_path = base_path.join("overlay2");
+    let mount_count = read_mountinfo().iter()
+        .filter(|mount| mount.fs_type == "overlay" && mount.mount_point.starts_with(&overlay2_path))
+        .count();
+    if mount_count > 0 {
+        reasons.push(format!("{} overlay mount(s) active under {}", mount_count, overlay2_path.display()));
+    }
+
+    // with systemd socket activation the socket exists while dockerd is stopped, and connecting
+    // to it would start dockerd, so it only backs up another sign
+    if !reasons.is_empty() && Path::new(DOCKER_SOCKET).exists() {
+        reasons.push(format!("docker socket {} exists", DOCKER_SOCKET));
+    }
+
+    reasons
+}
+
+/// Pids of the processes named `name`, empty if /proc is not available
+fn pids_named(name: &str) -> Vec<String> {
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut pids: Vec<String> = entries.flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .filter(|pid| pid.chars().all(|c| c.is_ascii_digit()))
+        .filter(|pid| fs::read_to_string(Path::new("/proc").join(pid).join("comm")).is_ok_and(|comm| comm.trim() == name))
+        .collect();
+    pids.sort();
+    pids
+}
+
+/// Fails when the docker daemon looks alive, unless `force` is set
+pub fn ensure_daemon_stopped(base_path: &Path, containerd_path: Option<&Path>, force: bool) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    let reasons = daemon_activity(base_path, containerd_path);
+    if !reasons.is_empty() {
+        anyhow::bail!("Docker daemon seems to be active, refusing to delete (use --force to override):\n - {}", reasons.join("\n - "));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn processes_are_found_by_name() {
+        let name = fs::read_to_string("/proc/self/comm").unwrap();
+        assert!(pids_named(name.trim()).contains(&std::process::id().to_string()));
+        assert!(pids_named("no-such-process-name").is_empty());
+    }
+}
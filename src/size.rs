@@ -0,0 +1,117 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Bytes allocated on disk by the given files and directories, directories are walked recursively.
+/// Symlinks and mount points are not followed, and an inode already in `seen` is not counted twice,
+/// so hard links shared between overlay2 `diff` dirs are only accounted once.
+pub fn disk_usage(paths: &[PathBuf], seen: &mut HashSet<(u64, u64)>) -> u64 {
+    let mut total = 0;
+    for path in paths {
+        walk(path, None, &mut |inode, bytes| {
+            if !seen.insert(inode) {
+                return false;
+            }
+            total += bytes;
+            true
+        });
+    }
+    total
+}
+
+/// Bytes allocated by each inode under the given paths, walked like `disk_usage`.
+/// Lets the size of a node and its share of a total be computed with a single walk.
+pub fn inode_usage(paths: &[PathBuf]) -> HashMap<(u64, u64), u64> {
+    let mut inodes = HashMap::new();
+    for path in paths {
+        walk(path, None, &mut |inode, bytes| inodes.insert(inode, bytes).is_none());
+    }
+    inodes
+}
+
+// `visit` gets each inode with its allocated bytes, and returns false for an inode already visited.
+// Like `du -x`, the walk stays on the device of the first path: the shm of a container or the
+// merged dir of a running overlay are mounts, their files are not on the docker disk.
+fn walk(path: &Path, device: Option<u64>, visit: &mut dyn FnMut((u64, u64), u64) -> bool) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return,
+    };
+    if device.is_some_and(|device| device != metadata.dev()) {
+        return;
+    }
+    if !visit((metadata.dev(), metadata.ino()), metadata.blocks() * 512) {
+        return;
+    }
+
+    if metadata.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.flatten() {
+                walk(&entry.path(), Some(metadata.dev()), visit);
+            }
+        }
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::write;
+
+    #[test]
+    fn hard_links_are_counted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lower/diff/file");
+        write(&file, vec![1u8; 64 * 1024]);
+        let alone = disk_usage(std::slice::from_ref(&file), &mut HashSet::new());
+        assert!(alone >= 64 * 1024);
+
+        fs::create_dir_all(dir.path().join("upper/diff")).unwrap();
+        fs::hard_link(&file, dir.path().join("upper/diff/file")).unwrap();
+        let mut seen = HashSet::new();
+        let lower = disk_usage(&[dir.path().join("lower")], &mut seen);
+        let upper = disk_usage(&[dir.path().join("upper")], &mut seen);
+        // the upper dir only adds its own two directories
+        assert!(lower >= alone);
+        assert!(upper < alone);
+        assert_eq!(inode_usage(&[dir.path().to_path_buf()]).values().sum::<u64>(), disk_usage(&[dir.path().to_path_buf()], &mut HashSet::new()));
+    }
+
+    #[test]
+    fn walk_stays_on_the_device_it_starts_on() {
+        // /dev/pts and /dev/shm are usually mounts of their own
+        let device = fs::metadata("/dev").unwrap().dev();
+        let mut devices = HashSet::new();
+        walk(Path::new("/dev"), None, &mut |(device, _), _| {
+            devices.insert(device);
+            true
+        });
+        assert_eq!(devices, HashSet::from([device]));
+    }
+
+    #[test]
+    fn sizes_are_formatted_in_binary_units() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+        assert_eq!(format_size(2048 * 1024u64.pow(4)), "2048.0 TiB");
+    }
+}
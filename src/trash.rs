@@ -0,0 +1,278 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Quarantined files are moved under `<base>/TRASH_DIR/<timestamp>/`, keeping their path relative to base
+pub const TRASH_DIR: &str = ".docker-cleaner-trash";
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// How `Node::delete` gets rid of its files
+pub enum Remover {
+    Delete,
+    Quarantine(Trash),
+}
+
+impl Remover {
+    pub fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Remover::Delete => fs::remove_dir_all(path),
+            Remover::Quarantine(trash) => trash.move_aside(path),
+        }
+    }
+
+    pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Remover::Delete => fs::remove_file(path),
+            Remover::Quarantine(trash) => trash.move_aside(path),
+        }
+    }
+
+    /// Called before `path` is edited in place, quarantine keeps a copy of it so restore can put it back
+    pub fn keep_before_edit(&self, path: &Path) -> io::Result<()> {
+        match self {
+            Remover::Delete => Ok(()),
+            Remover::Quarantine(trash) => trash.keep_copy(path),
+        }
+    }
+
+    /// Where removed paths go, recorded in the journal, None when they are deleted
+    pub fn quarantine_root(&self) -> Option<&Path> {
+        match self {
+            Remover::Delete => None,
+            Remover::Quarantine(trash) => Some(trash.root()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    base: PathBuf,
+    created: String,
+    /// Paths relative to base, in the order they were moved
+    entries: Vec<PathBuf>,
+    /// Paths relative to base of the files edited in place, copied before their first edit
+    #[serde(default)]
+    copies: Vec<PathBuf>,
+}
+
+pub struct Trash {
+    base_path: PathBuf,
+    root: PathBuf,
+    manifest: RefCell<Manifest>,
+}
+
+impl Trash {
+    /// The trash directory is only created when the first file is moved into it
+    pub fn new(base_path: &Path) -> Trash {
+        let now = chrono::Utc::now();
+        let trash_path = base_path.join(TRASH_DIR);
+        let name = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let mut root = trash_path.join(&name);
+        let mut suffix = 1;
+        while root.exists() {
+            root = trash_path.join(format!("{}-{}", name, suffix));
+            suffix += 1;
+        }
+
+        Trash {
+            base_path: base_path.to_path_buf(),
+            root,
+            manifest: RefCell::new(Manifest {
+                base: base_path.to_path_buf(),
+                created: now.to_rfc3339(),
+                entries: Vec::new(),
+                copies: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> io::Result<&'a Path> {
+        path.strip_prefix(&self.base_path)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not under {}", path.display(), self.base_path.display())))
+    }
+
+    fn move_aside(&self, path: &Path) -> io::Result<()> {
+        let relative = self.relative(path)?;
+        let target = self.root.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(path, &target)?;
+
+        let mut manifest = self.manifest.borrow_mut();
+        manifest.entries.push(relative.to_path_buf());
+        self.write_manifest(&manifest)
+    }
+
+    // only the first copy is kept, it is the file as it was before the cleanup
+    fn keep_copy(&self, path: &Path) -> io::Result<()> {
+        let relative = self.relative(path)?;
+        let mut manifest = self.manifest.borrow_mut();
+        if manifest.copies.iter().any(|copy| copy == relative) {
+            return Ok(());
+        }
+        let target = self.root.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(path, &target)?;
+
+        manifest.copies.push(relative.to_path_buf());
+        self.write_manifest(&manifest)
+    }
+
+    // written after each change, so the manifest is right even if the cleanup is interrupted
+    fn write_manifest(&self, manifest: &Manifest) -> io::Result<()> {
+        let content = serde_json::to_string_pretty(manifest)?;
+        fs::write(self.root.join(MANIFEST_FILE), content)
+    }
+}
+
+/// Names of the quarantines available under `base_path`, oldest first
+pub fn list_quarantines(base_path: &Path) -> Result<Vec<String>> {
+    let trash_path = base_path.join(TRASH_DIR);
+    if !trash_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(&trash_path).context(format!("Failed to read {}", trash_path.display()))? {
+        let entry = entry?;
+        if entry.path().join(MANIFEST_FILE).exists() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Moves every entry of the quarantine back to its place, then removes the quarantine.
+/// Entries whose original path exists again are left in the quarantine and reported.
+/// The copies of the files edited in place replace them, later edits of these files are lost.
+pub fn restore(base_path: &Path, name: &str) -> Result<()> {
+    let root = base_path.join(TRASH_DIR).join(name);
+    let manifest_path = root.join(MANIFEST_FILE);
+    let content = fs::read_to_string(&manifest_path).context(format!("Failed to read {}", manifest_path.display()))?;
+    let manifest: Manifest = serde_json::from_str(&content).context(format!("Failed to parse {}", manifest_path.display()))?;
+
+    let mut failed = Vec::new();
+    let mut remaining = Vec::new();
+    for relative in manifest.entries.iter().rev() {
+        let source = root.join(relative);
+        let target = base_path.join(relative);
+        let result = if target.exists() {
+            Err(io::Error::new(io::ErrorKind::AlreadyExists, "already exists"))
+        } else {
+            target.parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::rename(&source, &target))
+        };
+        match result {
+            Ok(_) => println!("restored {}", target.display()),
+            Err(e) => {
+                failed.push(format!("{}: {}", target.display(), e));
+                remaining.insert(0, relative.clone());
+            }
+        }
+    }
+
+    let mut remaining_copies = Vec::new();
+    for relative in &manifest.copies {
+        let target = base_path.join(relative);
+        match fs::rename(root.join(relative), &target) {
+            Ok(_) => println!("restored {} as it was before the cleanup", target.display()),
+            Err(e) => {
+                failed.push(format!("{}: {}", target.display(), e));
+                remaining_copies.push(relative.clone());
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        // the manifest only keeps what is still in the quarantine, so restore can be run again
+        let manifest = Manifest { entries: remaining, copies: remaining_copies, ..manifest };
+        fs::write(&manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+        anyhow::bail!("{} entry(ies) could not be restored, {} is kept:\n - {}", failed.len(), root.display(), failed.join("\n - "));
+    }
+    fs::remove_dir_all(&root).context(format!("Failed to remove {}", root.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quarantine_is_restored() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let layer = base.join("overlay2/cache0");
+        fs::create_dir_all(layer.join("diff")).unwrap();
+        fs::write(layer.join("diff/file"), "content").unwrap();
+        let link = base.join("overlay2/l/SHORT0");
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink("../cache0/diff", &link).unwrap();
+
+        let remover = Remover::Quarantine(Trash::new(base));
+        remover.remove_file(&link).unwrap();
+        remover.remove_dir_all(&layer).unwrap();
+        assert!(!layer.exists());
+        assert!(fs::symlink_metadata(&link).is_err());
+
+        let names = list_quarantines(base).unwrap();
+        assert_eq!(names.len(), 1);
+        let root = base.join(TRASH_DIR).join(&names[0]);
+        let manifest: Manifest = serde_json::from_str(&fs::read_to_string(root.join(MANIFEST_FILE)).unwrap()).unwrap();
+        assert_eq!(manifest.entries, vec![PathBuf::from("overlay2/l/SHORT0"), PathBuf::from("overlay2/cache0")]);
+
+        restore(base, &names[0]).unwrap();
+        assert_eq!(fs::read_to_string(layer.join("diff/file")).unwrap(), "content");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../cache0/diff"));
+        assert!(!root.exists());
+        assert!(list_quarantines(base).unwrap().is_empty());
+    }
+
+    #[test]
+    fn edited_file_is_restored_as_it_was_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let file = base.join("image/overlay2/repositories.json");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "first").unwrap();
+
+        let remover = Remover::Quarantine(Trash::new(base));
+        remover.keep_before_edit(&file).unwrap();
+        fs::write(&file, "second").unwrap();
+        remover.keep_before_edit(&file).unwrap();
+        fs::write(&file, "third").unwrap();
+
+        let name = list_quarantines(base).unwrap().remove(0);
+        restore(base, &name).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "first");
+        assert!(list_quarantines(base).unwrap().is_empty());
+    }
+
+    #[test]
+    fn restore_keeps_what_is_back_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path();
+        let file = base.join("image/overlay2/imagedb/content/sha256/abc");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(&file, "old").unwrap();
+
+        Remover::Quarantine(Trash::new(base)).remove_file(&file).unwrap();
+        fs::write(&file, "new").unwrap();
+
+        let name = list_quarantines(base).unwrap().remove(0);
+        assert!(restore(base, &name).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        assert_eq!(list_quarantines(base).unwrap(), vec![name]);
+    }
+}
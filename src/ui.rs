@@ -1,66 +1,237 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 use std::cell::RefCell;
-use std::path::PathBuf;
 use cursive::Cursive;
 use cursive::theme::{BaseColor, Color, Effect, Style};
 use cursive::traits::*;
 use cursive::utils::markup::StyledString;
-use cursive::views::{Dialog, SelectView, TextView, LinearLayout, ScrollView};
+use cursive::event::{Event, Key};
+use cursive::menu;
+use cursive::utils::Counter;
+use cursive::views::{Dialog, EditView, OnEventView, ProgressBar, SelectView, TextView, LinearLayout, ScrollView};
 use crate::node::Node;
-use crate::analysis::{classify_layers, remove_node, remove_node_list};
+use crate::analysis::{build_graph, check_plan, classify_layers, dangling_nodes, explain_tree, find_node, remove_node, remove_step, DeleteOptions, DeletionPlan, GraphDiff, Store, DANGLING_CATEGORIES};
+use crate::safety::daemon_activity;
+use crate::size::{format_size, inode_usage};
 
 // Define a struct to hold all your user data
-#[allow(dead_code)]
 struct UiAppState {
-    base_path: PathBuf,
+    delete_options: DeleteOptions,
+    /// Scanned again on rescan
+    store: Store,
     graph: HashMap<String, Rc<RefCell<dyn Node>>>,
+    bulk: Option<BulkDeletion>,
+    /// Layers built from the graph, bottom first, with their position in the layer stack.
+    /// Rebuilt each time the graph changes. Dialogs, like the search, are layers too but not screens.
+    screens: Vec<(Screen, usize)>,
+    /// Disk usage of the nodes listed in the category screens, by node id, until the graph changes
+    sizes: HashMap<String, u64>,
+    /// Disk usage of the category screens, by category and dangling flag, until the graph changes
+    totals: HashMap<(String, bool), u64>,
+}
+
+#[derive(Clone)]
+enum Screen {
+    Main,
+    Category { category: String, dangling: bool },
+    Node(String),
 }
 
 struct UiMainNode {
-    pub desc: &'static str,
-    pub node_type: &'static str,
+    desc: &'static str,
+    node_type: &'static str,
 }
 
 // Define the static list for the main nodes outside of main
 static UPPER_NODES: &[UiMainNode] = &[
     UiMainNode { desc: "Images", node_type: "ImageRepo" },
     UiMainNode { desc: "Containers", node_type: "Container" },
+    UiMainNode { desc: "Containerd Images", node_type: "ContainerdImage" },
     //UiMainNode { desc: "Volumes", node_type: "Mount" },
    // UiMainNode { desc: "Networks", node_type: "network" },
 ];
 
-// Define the static list for the main nodes outside of main
-static DANDLING_NODES: &[UiMainNode] = &[
-    UiMainNode { desc: "MetadataDiffId", node_type: "MetadataDiffId" },
-    UiMainNode { desc: "LayerDiffId", node_type: "LayerDiffId" },
-    UiMainNode { desc: "ImageContent", node_type: "ImageContent" },
-    UiMainNode { desc: "ImageLayer", node_type: "ImageLayer" },
-    UiMainNode { desc: "Overlay2", node_type: "Overlay2" },
-    UiMainNode { desc: "Mount", node_type: "Mount" },
+pub fn run_ui(graph: HashMap<String, Rc<RefCell<dyn Node>>>, delete_options: DeleteOptions, store: Store) -> anyhow::Result<()> {
+    let mut siv = cursive::default();
 
-    UiMainNode { desc: "Images", node_type: "ImageRepo" },
-];
+    siv.set_user_data(UiAppState {
+        delete_options,
+        store,
+        graph,
+        bulk: None,
+        screens: Vec::new(),
+        sizes: HashMap::new(),
+        totals: HashMap::new(),
+    });
+    show_main(&mut siv);
+    // an EditView having the focus keeps the '/' typed in it
+    siv.add_global_callback('/', show_search);
+    siv.add_global_callback(Event::CtrlChar('f'), show_search);
+    siv.add_global_callback(Key::F5, rescan);
+    siv.add_global_callback(Key::F10, |s| s.select_menubar());
+    // an EditView keeps the Backspace typed in it, and Esc goes back from a search anyway
+    siv.add_global_callback(Key::Esc, go_back);
+    siv.add_global_callback(Key::Backspace, go_back);
+    siv.add_global_callback(Event::CtrlChar('t'), go_to_top);
+
+    siv.menubar()
+        .add_subtree("Docker Cleaner", menu::Tree::new()
+            .leaf("Search  /", show_search)
+            .leaf("Rescan  F5", rescan)
+            .leaf("Back to top  Ctrl-T", go_to_top)
+            .delimiter()
+            .leaf("Quit", |s| s.quit()));
+    siv.set_autohide_menu(false);
 
-pub fn run_ui(graph: HashMap<String, Rc<RefCell<dyn Node>>>, base_path: PathBuf) -> anyhow::Result<()> {
-    let mut siv = cursive::default();
+    siv.run();
+
+    Ok(())
+}
 
-    let _app_state = UiAppState {
-        base_path,
-        graph: graph.clone(),
+/// Reads the docker directory again, for changes made while the UI is open, and shows what changed
+fn rescan(s: &mut Cursive) {
+    // the graph is not swapped under a bulk deletion
+    if s.with_user_data(|state: &mut UiAppState| state.bulk.is_some()).unwrap_or(false) {
+        return;
+    }
+    let result = s.with_user_data(|state: &mut UiAppState| {
+        let graph = build_graph(&state.delete_options.base_path, &state.store)?;
+        let diff = GraphDiff::new(&state.graph, &graph);
+        state.graph = graph;
+        Ok::<GraphDiff, anyhow::Error>(diff)
+    }).unwrap();
+    let diff = match result {
+        Ok(diff) => diff,
+        Err(e) => {
+            s.add_layer(Dialog::info(format!("Error scanning: {:#}", e)));
+            return;
+        }
     };
 
-    siv.set_user_data(graph);
-    siv.add_layer(Dialog::around(build_main_view())
-        .title("Docker Cleaner")
-        .button("Quit", |s| s.quit()));
+    refresh_screens(s);
+    if diff.is_empty() {
+        s.add_layer(Dialog::info("Nothing changed since the previous scan"));
+        return;
+    }
+    let mut lines = Vec::new();
+    lines.extend(diff.added.iter().map(|node_id| format!("+ {}", node_id)));
+    lines.extend(diff.removed.iter().map(|node_id| format!("- {}", node_id)));
+    lines.extend(diff.changed.iter().map(|node_id| format!("~ {}", node_id)));
+    s.add_layer(Dialog::around(ScrollView::new(TextView::new(format!(
+            "{} added, {} removed, {} changed\n\n{}",
+            diff.added.len(), diff.removed.len(), diff.changed.len(), lines.join("\n")))))
+        .title("Rescan")
+        .button("Close", |s| { s.pop_layer(); }));
+}
 
-    siv.run();
+/// Closes the top layer, a screen or a dialog opened over it. The main screen is never closed.
+fn go_back(s: &mut Cursive) {
+    let top_screen = s.with_user_data(|state: &mut UiAppState| {
+        // the progress of a bulk deletion stays until it is over
+        if state.bulk.is_some() { None } else { Some((state.screens.last().map(|(_, layer)| *layer), state.screens.len())) }
+    }).flatten();
+    let (top_screen_layer, screen_count) = match top_screen {
+        Some(top_screen) => top_screen,
+        None => return,
+    };
 
-    Ok(())
+    // a dialog may sit between two screens, like the search under the node picked from it
+    let top_layer = s.screen().len() - 1;
+    if top_screen_layer != Some(top_layer) {
+        s.pop_layer();
+    } else if screen_count > 1 {
+        close_screen(s);
+    }
+}
+
+/// Closes everything above the main screen
+fn go_to_top(s: &mut Cursive) {
+    let bulk = s.with_user_data(|state: &mut UiAppState| state.bulk.is_some()).unwrap_or(false);
+    if bulk {
+        return;
+    }
+    while s.screen().len() > 1 {
+        s.pop_layer();
+    }
+    s.with_user_data(|state: &mut UiAppState| state.screens.truncate(1));
+}
+
+/// Path from the main screen to `screen`, e.g. Main → Images → ImageRepo:nginx:latest → ImageContent:1c1f4bd3a61d
+fn breadcrumb(s: &mut Cursive, screen: &Screen) -> String {
+    let mut screens: Vec<Screen> = s.with_user_data(|state: &mut UiAppState| {
+        state.screens.iter().map(|(screen, _)| screen.clone()).collect()
+    }).unwrap_or_default();
+    screens.push(screen.clone());
+    screens.iter()
+        .map(|screen| match screen {
+            Screen::Main => "Main".to_string(),
+            Screen::Category { category, dangling: true } => format!("{} (dangling)", category),
+            Screen::Category { category, dangling: false } => category.clone(),
+            Screen::Node(node_id) => short_id(node_id),
+        })
+        .collect::<Vec<String>>()
+        .join(" → ")
+}
+
+// sha256 ids are cut to 12 characters, like docker does
+fn short_id(node_id: &str) -> String {
+    match node_id.rsplit_once(':') {
+        Some((prefix, sha)) if sha.len() == 64 && sha.chars().all(|c| c.is_ascii_hexdigit()) => format!("{}:{}", prefix, &sha[..12]),
+        _ => node_id.to_string(),
+    }
 }
 
-fn build_main_view() -> impl View {
+/// Records the layer just added as `screen`
+fn push_screen(s: &mut Cursive, screen: Screen) {
+    let layer = s.screen().len() - 1;
+    s.with_user_data(|state: &mut UiAppState| state.screens.push((screen, layer)));
+}
+
+/// Back button of the screens
+fn close_screen(s: &mut Cursive) {
+    s.pop_layer();
+    s.with_user_data(|state: &mut UiAppState| state.screens.pop());
+}
+
+/// Rebuilds every screen from the graph after it changed, the details of the nodes that are gone are dropped.
+/// Other dialogs are closed.
+fn refresh_screens(s: &mut Cursive) {
+    let screens = s.with_user_data(|state: &mut UiAppState| {
+        // every change of the graph ends here, the sizes are walked again
+        state.sizes.clear();
+        state.totals.clear();
+        let screens = std::mem::take(&mut state.screens);
+        screens.into_iter()
+            .map(|(screen, _)| screen)
+            .filter(|screen| match screen {
+                Screen::Node(node_id) => find_node(&state.graph, node_id).is_some(),
+                _ => true,
+            })
+            .collect::<Vec<Screen>>()
+    }).unwrap_or_default();
+
+    while s.pop_layer().is_some() {}
+    for screen in screens {
+        match screen {
+            Screen::Main => show_main(s),
+            Screen::Category { category, dangling } => show_category_details(s, &category, dangling),
+            Screen::Node(node_id) => show_node_details(s, node_id),
+        }
+    }
+}
+
+fn show_main(s: &mut Cursive) {
+    let view = s.with_user_data(|state: &mut UiAppState| build_main_view(&state.graph)).unwrap();
+    s.add_layer(Dialog::around(view)
+        .title("Docker Cleaner")
+        .button("Search", show_search)
+        .button("Quit", |s| s.quit()));
+    push_screen(s, Screen::Main);
+}
+
+fn build_main_view(graph: &HashMap<String, Rc<RefCell<dyn Node>>>) -> impl View {
+    let classified = classify_layers(graph);
+    let count = |category: &str| classified.get(category).map_or(0, Vec::len);
 
     // Create the "Upper node" section
     let mut upper_select = SelectView::new()
@@ -77,18 +248,26 @@ fn build_main_view() -> impl View {
             show_category_details(s, item, true);
         });
 
-    for node in DANDLING_NODES.iter() {
-        dangling_select.add_item(node.desc, node.node_type);
+    for category in DANGLING_CATEGORIES.iter() {
+        let dangling_count = dangling_nodes(graph, category.node_type).len();
+        dangling_select.add_item(format!("{} ({})", category.desc, dangling_count), category.node_type);
     }
 
     // Create the "Missing node" section
     let missing_select = SelectView::new()
-        .item("Missing nodes", "MissingNode")
+        .item(format!("Missing nodes ({})", count("MissingNode")), "MissingNode")
+        .on_submit(move |s, item: &str| {
+            show_category_details(s, item, false);
+        });
+
+    // Create the "Corrupt node" section, for the files the analyzers could not read
+    let corrupt_select = SelectView::new()
+        .item(format!("Corrupt nodes ({})", count("Corrupt")), "Corrupt")
         .on_submit(move |s, item: &str| {
             show_category_details(s, item, false);
         });
 
-    return LinearLayout::vertical()
+    LinearLayout::vertical()
         .child( TextView::new(StyledString::styled(
             "Upper level nodes",
             Style::from(Effect::Bold).combine(Effect::Underline).combine(Color::Dark(BaseColor::Red)),
@@ -106,94 +285,195 @@ fn build_main_view() -> impl View {
             Style::from(Effect::Bold).combine(Effect::Underline).combine(Color::Dark(BaseColor::Red)),
         )))
         .child(missing_select)
-        .child(TextView::new("                                 "));
+        .child(TextView::new("                                 "))
+        .child( TextView::new(StyledString::styled(
+            "Corrupt nodes",
+            Style::from(Effect::Bold).combine(Effect::Underline).combine(Color::Dark(BaseColor::Red)),
+        )))
+        .child(corrupt_select)
+        .child(TextView::new("                                 "))
 }
 
 
-fn show_category_details(s: &mut Cursive, category: &str, dandling: bool) {
-
-    let classified = s.with_user_data(|graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>| {
-        classify_layers(&graph)
-    }).unwrap();
+// Only the best matches are listed, the query is refined until the node shows up
+const SEARCH_RESULTS_LIMIT: usize = 200;
 
-    let mut nodes: Vec<Rc<RefCell<dyn Node>>> = match classified.get(category) {
-        Some(vec) => {
-            if dandling {
-                vec
-                .iter()
-                .filter(|node_ref| {
-                    let node = node_ref.borrow();
-                    node.rdeps().len() == 0
-                })
-                .cloned() //  .map(Rc::clone)
-                .collect()
-            } else {
-                vec.clone()
+fn show_search(s: &mut Cursive) {
+    // a second dialog would reuse the search_results name, and the results would go to the first one
+    if s.find_name::<SelectView<String>>("search_results").is_some() {
+        return;
+    }
+    let results = SelectView::<String>::new()
+        .on_submit(|s, node_id: &String| {
+            show_node_details(s, node_id.clone());
+        })
+        .with_name("search_results");
+    let edit = EditView::new()
+        .on_edit(|s, query, _| update_search_results(s, query))
+        .on_submit(|s, _| {
+            // Enter in the filter box jumps to the best match
+            let node_id = s.call_on_name("search_results", |results: &mut SelectView<String>| {
+                results.get_item(0).map(|(_, node_id)| node_id.clone())
+            }).flatten();
+            if let Some(node_id) = node_id {
+                show_node_details(s, node_id);
             }
+        })
+        .fixed_width(80);
+
+    s.add_layer(Dialog::around(LinearLayout::vertical()
+            .child(edit)
+            .child(TextView::new("                                 "))
+            .child(ScrollView::new(results).min_height(10)))
+        .title("Search nodes")
+        .button("Back", |s| { s.pop_layer(); }));
+    update_search_results(s, "");
+}
+
+fn update_search_results(s: &mut Cursive, query: &str) {
+    let matches = s.with_user_data(|state: &mut UiAppState| {
+        search_nodes(&state.graph, query)
+    }).unwrap_or_default();
+
+    s.call_on_name("search_results", |results: &mut SelectView<String>| {
+        results.clear();
+        for (node_id, label) in matches.into_iter().take(SEARCH_RESULTS_LIMIT) {
+            results.add_item(label, node_id);
         }
-        
-        None => Vec::new()
-    };
-    nodes
-        .sort_by_key(|node| node.borrow().id());
+    });
+}
+
+/// Ids and labels of the nodes matching `query` over all the categories, best matches first.
+/// The label is searched too, so containers can be found by name and images by tag.
+fn search_nodes(graph: &HashMap<String, Rc<RefCell<dyn Node>>>, query: &str) -> Vec<(String, String)> {
+    // aliases point to a node already in the graph under its id
+    let nodes: HashMap<String, String> = graph.values()
+        .map(|node| (node.borrow().id(), node.borrow().label()))
+        .collect();
+    let mut matches: Vec<(u8, String, String)> = nodes.into_iter()
+        .filter_map(|(node_id, label)| match_rank(query, &label).map(|rank| (rank, node_id, label)))
+        .collect();
+    matches.sort();
+    matches.into_iter().map(|(_, node_id, label)| (node_id, label)).collect()
+}
+
+/// Rank of `text` for `query`, lower is better: 0 when one of its words or `:` separated parts
+/// starts with the query, 1 when it contains the query, 2 when the query characters only appear in order
+fn match_rank(query: &str, text: &str) -> Option<u8> {
+    let query = query.to_lowercase();
+    let text = text.to_lowercase();
+    if text.split([':', ' ', '/']).any(|part| part.starts_with(&query)) {
+        return Some(0);
+    }
+    if text.contains(&query) {
+        return Some(1);
+    }
+    let mut chars = text.chars();
+    if query.chars().all(|c| chars.any(|t| t == c)) {
+        return Some(2);
+    }
+    None
+}
+
+fn show_category_details(s: &mut Cursive, category: &str, dandling: bool) {
+
+    let nodes = s.with_user_data(|state: &mut UiAppState| {
+        if dandling {
+            dangling_nodes(&state.graph, category)
+        } else {
+            let mut nodes = classify_layers(&state.graph).remove(category).unwrap_or_default();
+            nodes.sort_by_key(|node| node.borrow().id());
+            nodes
+        }
+    }).unwrap();
 
     let mut select = SelectView::new()
         .on_submit(move |s, item: &String| {
             show_node_details(s, item.clone());
         });
 
-    for node in &nodes {
+    let (sizes, total) = s.with_user_data(|state: &mut UiAppState| category_sizes(state, category, dandling, &nodes)).unwrap();
+    for (node, size) in nodes.iter().zip(sizes) {
         let node_id = node.borrow().id();
-        select.add_item(node_id.clone(), node_id);
+        let in_use = if node.borrow().in_use() { " [in use]" } else { "" };
+        select.add_item(format!("{} ({}){}", node.borrow().label(), format_size(size), in_use), node_id);
     }
 
-    s.add_layer(Dialog::around(ScrollView::new(select))
-        .title(format!("{} Details", category))
-        .button("Back", |s| { s.pop_layer(); })
-    /*
-        .button("Delete All", move |s| {
-            let result = s.with_user_data(|graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>| {
-                for node in &nodes {
-                    remove_node(graph, Rc::clone(&node), false);
-                }
-            });
-        }) */
-        );
+    let screen = Screen::Category { category: category.to_string(), dangling: dandling };
+    let header = breadcrumb(s, &screen);
+    let mut dialog = Dialog::around(LinearLayout::vertical()
+            .child(TextView::new(StyledString::styled(header, Effect::Italic)))
+            .child(TextView::new("                                 "))
+            .child(ScrollView::new(select)))
+        .title(format!("{} Details - {} nodes, {}{}", category, nodes.len(), format_size(total), if dandling { " reclaimable" } else { "" }))
+        .button("Back", close_screen);
+    // tags are what the user keeps, they are deleted one at a time
+    if dandling && !nodes.is_empty() && category != "ImageRepo" {
+        let category_all = category.to_string();
+        let category_recursive = category.to_string();
+        dialog = dialog
+            .button("Delete All", move |s| delete_all(s, &category_all, false))
+            .button("Delete All Recursive", move |s| delete_all(s, &category_recursive, true));
+    }
+    s.add_layer(dialog);
+    push_screen(s, screen);
+}
+
+/// Size of each node and total of the category, walked once and cached until the graph changes
+fn category_sizes(state: &mut UiAppState, category: &str, dangling: bool, nodes: &[Rc<RefCell<dyn Node>>]) -> (Vec<u64>, u64) {
+    let key = (category.to_string(), dangling);
+    let node_ids: Vec<String> = nodes.iter().map(|node| node.borrow().id()).collect();
+    if !state.totals.contains_key(&key) || !node_ids.iter().all(|node_id| state.sizes.contains_key(node_id)) {
+        // the inode set is shared, so the total does not count twice what several nodes hold
+        let mut seen = HashSet::new();
+        let mut total = 0;
+        for (node, node_id) in nodes.iter().zip(&node_ids) {
+            let inodes = inode_usage(&node.borrow().paths());
+            total += inodes.iter().filter(|(inode, _)| seen.insert(**inode)).map(|(_, bytes)| bytes).sum::<u64>();
+            state.sizes.insert(node_id.clone(), inodes.values().sum());
+        }
+        state.totals.insert(key.clone(), total);
+    }
+    (node_ids.iter().map(|node_id| state.sizes[node_id]).collect(), state.totals[&key])
 }
 
 fn show_node_details(s: &mut Cursive, node_id: String) {
-    let node = s.with_user_data(|graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>| {
-        Rc::clone(graph.get(&node_id).unwrap())
-    });
+    let node = s.with_user_data(|state: &mut UiAppState| {
+        find_node(&state.graph, &node_id)
+    }).flatten();
 
     if let Some(node) = node {
-        let node = node;
-        let details = format!(
+        let mut details = format!(
             "ID: {}",
             node.borrow().id()
         );
+        if let Some(reason) = node.borrow().in_use_reason() {
+            details.push_str(&format!("\nIn use: {}", reason));
+        }
+        for line in node.borrow().details() {
+            details.push('\n');
+            details.push_str(&line);
+        }
 
         let mut dependencies_select = SelectView::new()
         .on_submit(move |s, node_id: &str| {
-            let node_ref = s.with_user_data(|graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>| {
-                Rc::clone(graph.get(node_id).unwrap())
-            }).unwrap();
-            show_node_details(s, node_ref.borrow().id());
+            show_node_details(s, node_id.to_string());
         });
         for dep_node in node.borrow().deps().iter() {
-            dependencies_select.add_item(dep_node.borrow().id(), dep_node.borrow().id());
+            dependencies_select.add_item(dep_node.borrow().label(), dep_node.borrow().id());
         }
         let mut rdependencies_select = SelectView::new()
         .on_submit(move |s, node_id: &str| {
-            let node_ref = s.with_user_data(|graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>| {
-                Rc::clone(graph.get(node_id).unwrap())
-            }).unwrap();
-            show_node_details(s, node_ref.borrow().id());
+            show_node_details(s, node_id.to_string());
         });
         for rdep_node in node.borrow().rdeps().iter() {
-            rdependencies_select.add_item(rdep_node.borrow().id(), rdep_node.borrow().id());
+            rdependencies_select.add_item(rdep_node.borrow().label(), rdep_node.borrow().id());
         }
+        let screen = Screen::Node(node.borrow().id());
+        let header = breadcrumb(s, &screen);
         let view = LinearLayout::vertical()
+            .child(TextView::new(StyledString::styled(header, Effect::Italic)))
+            .child(TextView::new("                                 "))
             .child(TextView::new(details))
             .child(TextView::new("                                 "))
             .child( TextView::new(StyledString::styled(
@@ -209,9 +489,16 @@ fn show_node_details(s: &mut Cursive, node_id: String) {
             .child(rdependencies_select)
             .child(TextView::new("                                 "));
 
+        let node_explained = Rc::clone(&node);
         let mut node_detail = Dialog::around(view)
             .title("Node Details")
-            .button("Back", |s| { s.pop_layer(); });
+            .button("Back", close_screen)
+            .button("Explain", move |s| {
+                let tree = explain_tree(&node_explained).join("\n");
+                s.add_layer(Dialog::around(ScrollView::new(TextView::new(tree)))
+                    .title("Kept alive by")
+                    .button("Back", |s| { s.pop_layer(); }));
+            });
         let hard_deps_count = node.borrow().deps().iter().fold(0, |acc, node| {
             if !node.borrow().id().starts_with("Missing") {
                 acc + 1
@@ -219,7 +506,11 @@ fn show_node_details(s: &mut Cursive, node_id: String) {
                 acc
             }
         });
-        if node.borrow().rdeps().len() == 0 {
+        // a corrupt artifact goes away with the node it belongs to, and a containerd image
+        // only exists in meta.db, docker rmi removes it
+        let node_id = node.borrow().id();
+        let deletable = !node_id.starts_with("Corrupt:") && !node_id.starts_with("ContainerdImage:");
+        if node.borrow().rdeps().is_empty() && !node.borrow().in_use() && deletable {
             let node2 = Rc::clone(&node);
             node_detail = node_detail.button("Delete", move |s| {
                 delete_node(s, Rc::clone(&node2), false);
@@ -232,36 +523,104 @@ fn show_node_details(s: &mut Cursive, node_id: String) {
             }
         }
         s.add_layer(node_detail);
+        push_screen(s, screen);
+    } else {
+        s.add_layer(Dialog::info(format!("Node {} is not in the graph anymore", node_id)));
     }
 }
 
 fn delete_node(s: &mut Cursive, node: Rc<RefCell<dyn Node>>, recursive: bool) {
+    let node_id = node.borrow().id();
+
+    let plan = s.with_user_data(|state: &mut UiAppState| {
+        DeletionPlan::new(&state.graph, &node_id, recursive)
+    }).unwrap();
+    let question = if recursive {
+        format!("Are you sure you want to delete {} and deps ?", node_id)
+    } else {
+        format!("Are you sure you want to delete {} ?", node_id)
+    };
+    confirm_deletion(s, plan, question, false);
+}
+
+fn delete_all(s: &mut Cursive, category: &str, recursive: bool) {
+    let plan = s.with_user_data(|state: &mut UiAppState| {
+        let root_ids: Vec<String> = dangling_nodes(&state.graph, category).iter()
+            .map(|node| node.borrow().id())
+            .collect();
+        DeletionPlan::for_roots(&state.graph, &root_ids, recursive)
+    }).unwrap();
+    let question = format!("Are you sure you want to delete the {} dangling {} nodes{} ?",
+        plan.as_ref().map_or(0, |plan| plan.root_ids.len()), category, if recursive { " and deps" } else { "" });
+    confirm_deletion(s, plan, question, true);
+}
+
+/// Shows the plan and deletes on confirmation. A bulk plan goes on after a failed step and ends with a summary,
+/// a single one stops at the first error.
+fn confirm_deletion(s: &mut Cursive, plan: anyhow::Result<DeletionPlan>, question: String, bulk: bool) {
+    let plan = match plan {
+        Ok(plan) => plan,
+        Err(e) => {
+            s.add_layer(Dialog::info(format!("Error preparing deletion: {}", e)));
+            return;
+        }
+    };
 
-    let dep_list = s.with_user_data(|graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>| {
-        remove_node_list(graph, &node.borrow().id(), recursive)
-    }).unwrap().unwrap();
+    let (daemon_reasons, quarantine) = s.with_user_data(|state: &mut UiAppState| {
+        let daemon_reasons = if state.delete_options.force {
+            Vec::new()
+        } else {
+            daemon_activity(&state.delete_options.base_path, state.delete_options.containerd_path.as_deref())
+        };
+        let quarantine = state.delete_options.remover.quarantine_root().map(std::path::Path::to_path_buf);
+        (daemon_reasons, quarantine)
+    }).unwrap();
 
-    let result = dep_list.iter()
-    .fold(String::new(), |mut acc, item| {
-        acc.push_str("\n - ");
-        acc.push_str(&item.borrow().id());
+    let mut cumulative = 0;
+    let result = plan.steps.iter()
+    .fold(String::new(), |mut acc, step| {
+        cumulative += step.bytes;
+        acc.push_str(&format!("\n - {} ({}, cumulative {})", step.node_id, format_size(step.bytes), format_size(cumulative)));
         acc
     });
 
-    s.add_layer(Dialog::around(TextView::new(format!("Are you sure you want to delete {} and deps ?\n{}", node.borrow().id(), result)))
+    let mut text = format!("{}\n{}\n\n{} node(s), reclaimable: {}", question, result, plan.steps.len(), format_size(plan.total_bytes()));
+    if let Some(quarantine) = quarantine {
+        text.push_str(&format!("\nFiles are moved to {}, nothing is reclaimed until it is removed", quarantine.display()));
+    }
+
+    if !daemon_reasons.is_empty() {
+        s.add_layer(Dialog::around(ScrollView::new(TextView::new(format!(
+            "{}\n\nDeletion is blocked, the docker daemon seems to be active:\n - {}\n\nStop dockerd, or restart docker-cleaner with --force.",
+            text, daemon_reasons.join("\n - ")))))
+            .title("Deletion Blocked")
+            .button("Cancel", |s| { s.pop_layer(); }));
+        return;
+    }
+
+    let plan = Rc::new(plan);
+    s.add_layer(Dialog::around(ScrollView::new(TextView::new(text)))
         .title("Confirm Deletion")
         .button("Cancel", |s| { s.pop_layer(); })
         .button("Delete", move |s| {
-            let result = s.with_user_data(|graph: &mut HashMap<String, Rc<RefCell<dyn Node>>>| {
-                remove_node(graph, Rc::clone(&node), recursive)
+            if bulk {
+                start_bulk_deletion(s, Rc::clone(&plan));
+                return;
+            }
+
+            let result = s.with_user_data(|state: &mut UiAppState| {
+                remove_node(&mut state.graph, &plan, &state.delete_options)
             }).unwrap();
 
-            s.pop_layer();
+            // even a failed plan may have deleted some nodes
+            refresh_screens(s);
 
             match result {
-                Ok(_) => {
-                    s.pop_layer();
-                    s.add_layer(Dialog::info(format!("Node {} deleted successfully", node.borrow().id())));
+                Ok(warnings) if warnings.is_empty() => {
+                    s.add_layer(Dialog::info(format!("Node {} deleted successfully", plan.root_ids.join(", "))));
+                },
+                Ok(warnings) => {
+                    s.add_layer(Dialog::info(format!("Node {} deleted, with warnings:\n - {}", plan.root_ids.join(", "), warnings.join("\n - "))));
                 },
                 Err(e) => {
                     s.add_layer(Dialog::info(format!("Error deleting node: {}", e)));
@@ -269,3 +628,86 @@ fn delete_node(s: &mut Cursive, node: Rc<RefCell<dyn Node>>, recursive: bool) {
             }
         }));
 }
+
+/// A bulk deletion in progress, one step is executed on each refresh so the progress bar is drawn
+struct BulkDeletion {
+    plan: Rc<DeletionPlan>,
+    next: usize,
+    /// Node id of each executed step, with the warning of a deleted node or the error of a failed one
+    results: Vec<(String, Result<Option<String>, String>)>,
+    progress: Counter,
+}
+
+fn start_bulk_deletion(s: &mut Cursive, plan: Rc<DeletionPlan>) {
+    // nothing is deleted if the plan cannot be executed as a whole
+    let checked = s.with_user_data(|state: &mut UiAppState| {
+        check_plan(&plan, &state.delete_options)
+    }).unwrap();
+    if let Err(e) = checked {
+        s.add_layer(Dialog::info(format!("Error deleting nodes: {}", e)));
+        return;
+    }
+
+    let progress = Counter::new(0);
+    let total = plan.steps.len();
+    s.with_user_data(|state: &mut UiAppState| {
+        state.bulk = Some(BulkDeletion { plan, next: 0, results: Vec::new(), progress: progress.clone() });
+    });
+
+    s.pop_layer();
+    s.add_layer(OnEventView::new(Dialog::around(LinearLayout::vertical()
+            .child(TextView::new("").with_name("bulk_current"))
+            .child(ProgressBar::new().range(0, total).with_value(progress).min_width(60)))
+        .title("Deleting"))
+        .on_event(Event::Refresh, run_bulk_step));
+    s.set_autorefresh(true);
+}
+
+fn run_bulk_step(s: &mut Cursive) {
+    let current = s.with_user_data(|state: &mut UiAppState| {
+        let bulk = state.bulk.as_mut()?;
+        if bulk.next == bulk.plan.steps.len() {
+            return None;
+        }
+        let index = bulk.next;
+        let result = remove_step(&mut state.graph, &bulk.plan, index, &state.delete_options);
+        let node_id = bulk.plan.steps[index].node_id.clone();
+        bulk.results.push((node_id.clone(), result.map_err(|e| format!("{:#}", e))));
+        bulk.next += 1;
+        bulk.progress.set(bulk.next);
+        Some(node_id)
+    }).flatten();
+
+    if let Some(node_id) = current {
+        s.call_on_name("bulk_current", |view: &mut TextView| view.set_content(node_id));
+        return;
+    }
+
+    let bulk = s.with_user_data(|state: &mut UiAppState| state.bulk.take()).flatten();
+    let bulk = match bulk {
+        Some(bulk) => bulk,
+        None => return,
+    };
+    s.set_autorefresh(false);
+
+    let failed = bulk.results.iter().filter(|(_, result)| result.is_err()).count();
+    let reclaimed: u64 = bulk.plan.steps.iter().zip(&bulk.results)
+        .filter(|(_, (_, result))| result.is_ok())
+        .map(|(step, _)| step.bytes)
+        .sum();
+    let summary = bulk.results.iter()
+        .map(|(node_id, result)| match result {
+            Err(error) => format!("failed  {}: {}", node_id, error),
+            Ok(Some(warning)) => format!("warning {}", warning),
+            Ok(None) => format!("deleted {}", node_id),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    refresh_screens(s);
+    s.add_layer(Dialog::around(ScrollView::new(TextView::new(format!(
+            "{} node(s) deleted, {} failure(s), {} reclaimed\n\n{}",
+            bulk.results.len() - failed, failed, format_size(reclaimed), summary))))
+        .title("Deletion Summary")
+        .button("Close", |s| { s.pop_layer(); }));
+}